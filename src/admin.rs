@@ -0,0 +1,468 @@
+use crate::{
+    api,
+    auth::oidc::AuthClient,
+    maintenance::{self, Maintenance, MaintenanceWindow},
+    storage::{DocumentIdentifier, StorageConfig, StorageState, UserStorage},
+};
+use axum::{
+    async_trait,
+    body::{Body, StreamBody},
+    extract::{FromRequestParts, Path, Query},
+    http::{
+        header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tokio::io::ErrorKind;
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_MAINTENANCE_MESSAGE: &str = "Down for maintenance, writes are disabled.";
+const DEFAULT_RETRY_AFTER_SECS: u64 = 5 * 60;
+
+#[derive(Clone)]
+pub struct AdminConfig {
+    // Admin endpoints are disabled entirely while no token is configured
+    pub token: Option<String>,
+}
+
+pub fn router() -> Router<(), Body> {
+    Router::new()
+        .route("/users/:subject/document", get(entries))
+        .route("/users/:subject/document/:identifier", get(read))
+        .route("/users/:subject/export-and-delete", post(export_and_delete))
+        .route("/stats", get(stats))
+        .route("/users/:subject/features", get(features).put(set_features))
+        // Applies to the routes above, the maintenance window itself has to remain toggleable
+        .route_layer(middleware::from_fn(maintenance::guard))
+        .route("/maintenance", post(maintenance))
+}
+
+/// Proof that the request carried the configured admin bearer token
+pub struct Admin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let config = parts
+            .extensions
+            .get::<AdminConfig>()
+            .expect("missing AdminConfig extension");
+
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        match (&config.token, provided) {
+            (Some(expected), Some(provided)) if constant_time_eq(expected, provided) => Ok(Admin),
+            _ => {
+                warn!("Rejected admin request to {}", parts.uri.path());
+                Err(StatusCode::FORBIDDEN)
+            }
+        }
+    }
+}
+
+async fn entries(
+    _: Admin,
+    Path(subject): Path<String>,
+    query: Query<api::ListingQuery>,
+    representation: api::Representation,
+    Extension(config): Extension<StorageConfig>,
+    Extension(state): Extension<StorageState>,
+) -> Result<Response, StatusCode> {
+    let storage = impersonate(config, state, &subject)?;
+    info!("Admin listed documents of user {subject}");
+    api::entries(query, representation, storage)
+        .await
+        .map(IntoResponse::into_response)
+}
+
+async fn read(
+    _: Admin,
+    Path((subject, identifier)): Path<(String, DocumentIdentifier)>,
+    query: Query<api::ReadQuery>,
+    representation: api::Representation,
+    headers: HeaderMap,
+    Extension(config): Extension<StorageConfig>,
+    Extension(state): Extension<StorageState>,
+) -> Result<Response, StatusCode> {
+    let storage = impersonate(config, state, &subject)?;
+    info!("Admin read document {identifier} of user {subject}");
+    api::read(Path(identifier), query, representation, headers, storage).await
+}
+
+async fn export_and_delete(
+    _: Admin,
+    Path(subject): Path<String>,
+    Extension(config): Extension<StorageConfig>,
+    Extension(state): Extension<StorageState>,
+) -> Result<Response, StatusCode> {
+    let storage = impersonate(config, state, &subject)?;
+    info!("Admin started export and deletion of user {subject}");
+
+    // Writes wait until the user is deleted or the download is aborted, anything stored after
+    // the archive was built would otherwise be deleted without having been exported
+    let lock = storage.lock_exclusive().await;
+
+    let archive = storage.archive().await.map_err(|e| match e.kind() {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => {
+            warn!("Failed to export user {subject}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    let filename = format!("{subject}.zip");
+    let delete = stream::once(async move {
+        match storage.delete_all().await {
+            Ok(_) => info!("Admin deleted all data of user {subject} after export"),
+            Err(e) => warn!("Failed to delete user {subject} after export: {e}"),
+        }
+
+        drop(lock);
+    })
+    .filter_map(|_| async { None });
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/zip".to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        StreamBody::new(ReaderStream::with_capacity(archive, EXPORT_CHUNK_SIZE).chain(delete)),
+    )
+        .into_response())
+}
+
+async fn features(
+    _: Admin,
+    Path(subject): Path<String>,
+    Extension(config): Extension<StorageConfig>,
+    Extension(state): Extension<StorageState>,
+) -> Result<Json<BTreeSet<String>>, StatusCode> {
+    let storage = impersonate(config, state, &subject)?;
+
+    storage.features().await.map(Json).map_err(|e| {
+        warn!("Failed to read feature flags of user {subject}: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn set_features(
+    _: Admin,
+    Path(subject): Path<String>,
+    Extension(config): Extension<StorageConfig>,
+    Extension(state): Extension<StorageState>,
+    Json(features): Json<BTreeSet<String>>,
+) -> StatusCode {
+    let storage = match impersonate(config, state, &subject) {
+        Ok(storage) => storage,
+        Err(status) => return status,
+    };
+
+    match storage.set_features(&features).await {
+        Ok(_) => {
+            info!("Admin set feature flags of user {subject} to {features:?}");
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            warn!("Failed to write feature flags of user {subject}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+    message: Option<String>,
+    retry_after_secs: Option<u64>,
+}
+
+async fn maintenance(
+    _: Admin,
+    Extension(maintenance): Extension<Maintenance>,
+    Json(request): Json<MaintenanceRequest>,
+) -> StatusCode {
+    if request.enabled {
+        info!("Admin started a maintenance window");
+        maintenance.set(Some(MaintenanceWindow {
+            message: request
+                .message
+                .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_owned()),
+            retry_after_secs: request.retry_after_secs.unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+        }));
+    } else {
+        info!("Admin ended the maintenance window");
+        maintenance.set(None);
+    }
+
+    StatusCode::NO_CONTENT
+}
+
+fn impersonate(
+    config: StorageConfig,
+    state: StorageState,
+    subject: &str,
+) -> Result<UserStorage, StatusCode> {
+    // The subject ends up as a directory name so it must not be able to escape the storage root,
+    // nor reach hidden directories like the git repository
+    if subject.is_empty() || subject.starts_with('.') || subject.contains(['/', '\\']) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(UserStorage::new(config, state, subject))
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[derive(Serialize)]
+struct Stats {
+    pending_login_sessions: usize,
+}
+
+async fn stats(_: Admin, Extension(auth_client): Extension<AuthClient>) -> Json<Stats> {
+    Json(Stats {
+        pending_login_sessions: auth_client.pending_sessions(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::tests::body, storage::tests::config};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "admin-token";
+
+    fn app(config: StorageConfig) -> Router<(), Body> {
+        app_in_maintenance(config, Maintenance::default())
+    }
+
+    fn app_in_maintenance(config: StorageConfig, maintenance: Maintenance) -> Router<(), Body> {
+        router()
+            .layer(Extension(AdminConfig {
+                token: Some(TOKEN.to_owned()),
+            }))
+            .layer(Extension(config))
+            .layer(Extension(StorageState::default()))
+            .layer(Extension(maintenance))
+    }
+
+    async fn get(app: Router<(), Body>, uri: &str, token: &str) -> Response {
+        send(app, "GET", uri, token).await
+    }
+
+    async fn send(app: Router<(), Body>, method: &str, uri: &str, token: &str) -> Response {
+        send_json(app, method, uri, token, serde_json::Value::Null).await
+    }
+
+    async fn send_json(
+        app: Router<(), Body>,
+        method: &str,
+        uri: &str,
+        token: &str,
+        json: serde_json::Value,
+    ) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string()))
+            .unwrap();
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_lists_another_users_documents() {
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "bob");
+        let identifier = storage.create("Bob's entry".into()).await.unwrap();
+
+        let response = get(app(config), "/users/bob/document", TOKEN).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let listing: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(
+            listing[0]["identifier"],
+            serde_json::to_value(identifier).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn other_tokens_are_rejected() {
+        let response = get(app(config()), "/users/bob/document", "user-token").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn hidden_directories_cannot_be_impersonated() {
+        for subject in [".git", "..", ".hidden"] {
+            let response = get(app(config()), &format!("/users/{subject}/document"), TOKEN).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn listing_an_unknown_user_creates_nothing() {
+        let config = config();
+
+        let response = get(app(config.clone()), "/users/carol/document", TOKEN).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!config.location.join("carol").exists());
+    }
+
+    #[tokio::test]
+    async fn export_contains_everything_before_deleting_it() {
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "bob");
+        let identifier = storage.create("Bob's entry".into()).await.unwrap();
+        storage
+            .set_features(&BTreeSet::from(["beta".to_owned()]))
+            .await
+            .unwrap();
+
+        let response = send(
+            app(config.clone()),
+            "POST",
+            "/users/bob/export-and-delete",
+            TOKEN,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let archive = body(response).await;
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(str::to_owned).collect();
+        names.sort();
+        assert_eq!(names, [".features.json".into(), format!("{identifier}.md")]);
+
+        let mut document = String::new();
+        std::io::Read::read_to_string(
+            &mut archive.by_name(&format!("{identifier}.md")).unwrap(),
+            &mut document,
+        )
+        .unwrap();
+        assert_eq!(document, "Bob's entry");
+
+        assert!(!config.location.join("bob").exists());
+    }
+
+    #[tokio::test]
+    async fn aborted_export_deletes_nothing() {
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "bob");
+        storage.create("Bob's entry".into()).await.unwrap();
+
+        let response = send(
+            app(config.clone()),
+            "POST",
+            "/users/bob/export-and-delete",
+            TOKEN,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(response);
+
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_a_running_export() {
+        let config = config();
+        let state = StorageState::default();
+        let storage = UserStorage::new(config.clone(), state.clone(), "bob");
+        storage.create("Bob's entry".into()).await.unwrap();
+
+        let lock = storage.lock_exclusive().await;
+        let create = tokio::spawn({
+            let storage = UserStorage::new(config, state, "bob");
+            async move { storage.create("Written during the export".into()).await }
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!create.is_finished());
+        storage.delete_all().await.unwrap();
+        drop(lock);
+
+        create.await.unwrap().unwrap();
+        assert_eq!(storage.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn export_is_blocked_during_maintenance() {
+        let maintenance = Maintenance::default();
+        maintenance.set(Some(MaintenanceWindow {
+            message: DEFAULT_MAINTENANCE_MESSAGE.to_owned(),
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+        }));
+
+        let app = app_in_maintenance(config(), maintenance);
+        let response = send(app, "POST", "/users/bob/export-and-delete", TOKEN).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn maintenance_blocks_writes_until_disabled() {
+        let app = app(config());
+        let set_features = || {
+            send_json(
+                app.clone(),
+                "PUT",
+                "/users/bob/features",
+                TOKEN,
+                serde_json::json!(["beta"]),
+            )
+        };
+        let toggle = |enabled: bool| {
+            send_json(
+                app.clone(),
+                "POST",
+                "/maintenance",
+                TOKEN,
+                serde_json::json!({ "enabled": enabled, "message": "Backing up" }),
+            )
+        };
+
+        assert_eq!(toggle(true).await.status(), StatusCode::NO_CONTENT);
+
+        let response = set_features().await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key("retry-after"));
+        assert_eq!(body(response).await, b"Backing up");
+
+        let response = get(app.clone(), "/users/bob/features", TOKEN).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(toggle(false).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(set_features().await.status(), StatusCode::NO_CONTENT);
+    }
+}
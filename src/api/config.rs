@@ -0,0 +1,81 @@
+use crate::storage::{StorageConfig, UserStorage, TRUNCATE_LEN};
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+use time_tz::TimeZone;
+use tracing::warn;
+
+/// Settings clients may adapt to, never containing anything secret
+#[derive(Serialize)]
+pub struct ClientConfig {
+    default_preview_len: usize,
+    max_preview_len: usize,
+    max_listing: usize,
+    timezone: String,
+    trim_trailing_whitespace: bool,
+    ensure_final_newline: bool,
+    reject_binary_threshold: Option<f64>,
+    max_document_bytes: usize,
+
+    // Flags enabled for the requesting user
+    features: Vec<String>,
+}
+
+impl From<&StorageConfig> for ClientConfig {
+    fn from(config: &StorageConfig) -> Self {
+        Self {
+            default_preview_len: TRUNCATE_LEN.min(config.max_preview_len),
+            max_preview_len: config.max_preview_len,
+            max_listing: config.max_listing,
+            timezone: config.timezone.name().to_owned(),
+            trim_trailing_whitespace: config.trim_trailing_whitespace,
+            ensure_final_newline: config.ensure_final_newline,
+            reject_binary_threshold: config.reject_binary_threshold,
+            max_document_bytes: config.max_document_bytes,
+            features: Vec::new(),
+        }
+    }
+}
+
+pub async fn config(storage: UserStorage) -> Result<Json<ClientConfig>, StatusCode> {
+    let features = storage.features().await.map_err(|e| {
+        warn!("Failed to read feature flags: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(ClientConfig {
+        features: features.into_iter().collect(),
+        ..ClientConfig::from(storage.config())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{config, storage};
+
+    #[tokio::test]
+    async fn reported_limits_match_the_configuration() {
+        let storage = storage(StorageConfig {
+            max_document_bytes: 4096,
+            max_listing: 50,
+            max_preview_len: 256,
+            reject_binary_threshold: Some(0.3),
+            ensure_final_newline: true,
+            timezone: time_tz::timezones::get_by_name("Europe/Berlin").unwrap(),
+            ..config()
+        });
+
+        let Json(reported) = super::config(storage).await.unwrap();
+        let reported = serde_json::to_value(reported).unwrap();
+
+        assert_eq!(reported["max_document_bytes"], 4096);
+        assert_eq!(reported["max_listing"], 50);
+        assert_eq!(reported["max_preview_len"], 256);
+        assert_eq!(reported["default_preview_len"], 256);
+        assert_eq!(reported["reject_binary_threshold"], 0.3);
+        assert_eq!(reported["ensure_final_newline"], true);
+        assert_eq!(reported["trim_trailing_whitespace"], false);
+        assert_eq!(reported["timezone"], "Europe/Berlin");
+        assert_eq!(reported["features"], serde_json::json!([]));
+    }
+}
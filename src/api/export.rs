@@ -0,0 +1,134 @@
+use crate::{
+    markdown,
+    storage::{DocumentIdentifier, UserStorage},
+};
+use axum::{
+    body::StreamBody,
+    extract::Path,
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::IntoResponse,
+};
+use tokio::io::ErrorKind;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+const STYLESHEET: &str = r#"
+    body { max-width: 40em; margin: 2em auto; padding: 0 1em; font-family: Georgia, serif; line-height: 1.6; color: #222; }
+    header { color: #777; font-size: 0.9em; margin-bottom: 2em; }
+    pre, code { font-family: Menlo, Consolas, monospace; font-size: 0.9em; background: #f4f4f4; }
+    pre { padding: 0.5em; overflow-x: auto; }
+    blockquote { margin-left: 0; padding-left: 1em; border-left: 3px solid #ddd; color: #555; }
+    img { max-width: 100%; }
+    table { border-collapse: collapse; }
+    th, td { border: 1px solid #ddd; padding: 0.25em 0.5em; }
+"#;
+
+pub async fn export_html(
+    Path(identifier): Path<DocumentIdentifier>,
+    storage: UserStorage,
+) -> Result<impl IntoResponse, StatusCode> {
+    super::require_feature(&storage, super::FEATURE_HTML_EXPORT).await?;
+
+    let document = storage
+        .read(identifier, None)
+        .await
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => {
+                warn!("Failed to read document for export: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+    let title = document.date.as_deref().unwrap_or("Journal entry");
+    let body = markdown::to_html(&document.contents);
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>{STYLESHEET}</style>
+</head>
+<body>
+<header>{title}</header>
+<article>
+{body}</article>
+</body>
+</html>
+"#
+    );
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8".to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{identifier}.html\""),
+            ),
+        ],
+        html,
+    ))
+}
+
+pub async fn export_zip(storage: UserStorage) -> Result<impl IntoResponse, StatusCode> {
+    let archive = storage.archive_documents().await.map_err(|e| {
+        warn!("Failed to export documents: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/zip"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"journal-export.zip\"",
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(archive)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::tests::{body, TestApi},
+        storage::tests::config,
+    };
+    use axum::http::{header::CONTENT_DISPOSITION, StatusCode};
+
+    #[tokio::test]
+    async fn exported_html_is_standalone() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(
+            1,
+            "# Heading\n\nSome *emphasis*.\n\n<script>alert(1)</script>",
+        )])
+        .await;
+
+        let response = api.get("/api/document/1/export.html").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"1.html\""
+        );
+
+        let html = String::from_utf8(body(response).await).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.trim_end().ends_with("</html>"));
+        assert!(html.contains("<meta charset=\"utf-8\">"));
+        assert!(html.contains("<style>"));
+        assert!(html.contains("<h1>Heading</h1>"));
+        assert!(html.contains("<em>emphasis</em>"));
+
+        // Nothing that depends on the server or runs code
+        for external in ["<script", "<link", "src=", "href="] {
+            assert!(!html.contains(external), "contains {external}");
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use serde::Deserialize;
+use time::{Date, Month, Weekday};
+
+/// Comma separated list of weekdays, e.g. `sat,sun`
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+pub struct Weekdays(Vec<Weekday>);
+
+impl Weekdays {
+    pub fn contains(&self, weekday: Weekday) -> bool {
+        self.0.contains(&weekday)
+    }
+}
+
+impl TryFrom<String> for Weekdays {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split(',')
+            .map(|day| match day.trim().to_lowercase().as_str() {
+                "mon" | "monday" => Ok(Weekday::Monday),
+                "tue" | "tuesday" => Ok(Weekday::Tuesday),
+                "wed" | "wednesday" => Ok(Weekday::Wednesday),
+                "thu" | "thursday" => Ok(Weekday::Thursday),
+                "fri" | "friday" => Ok(Weekday::Friday),
+                "sat" | "saturday" => Ok(Weekday::Saturday),
+                "sun" | "sunday" => Ok(Weekday::Sunday),
+                _ => Err(format!("unknown weekday `{day}`")),
+            })
+            .collect::<Result<_, _>>()
+            .map(Weekdays)
+    }
+}
+
+/// Hours of the day from `start` (inclusive) to `end` (exclusive), e.g. `22-04`
+/// which wraps past midnight and covers 22:00 until 03:59
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct HourRange {
+    start: u8,
+    end: u8,
+}
+
+impl HourRange {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start < self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for HourRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid hour range `{value}`, expected e.g. `22-04`");
+
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let start: u8 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u8 = end.trim().parse().map_err(|_| invalid())?;
+
+        if start > 23 || end > 24 || start == end {
+            return Err(invalid());
+        }
+
+        Ok(HourRange { start, end })
+    }
+}
+
+/// Calendar date in the configured timezone, e.g. `2023-11-14`
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct LocalDate(Date);
+
+impl LocalDate {
+    pub fn date(self) -> Date {
+        self.0
+    }
+}
+
+impl TryFrom<String> for LocalDate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid date `{value}`, expected e.g. `2023-11-14`");
+
+        let mut parts = value.trim().splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let (year, month, day) = (next()?, next()?, next()?);
+
+        let year: i32 = year.parse().map_err(|_| invalid())?;
+        let month: u8 = month.parse().map_err(|_| invalid())?;
+        let day: u8 = day.parse().map_err(|_| invalid())?;
+
+        let month = Month::try_from(month).map_err(|_| invalid())?;
+        Date::from_calendar_date(year, month, day)
+            .map(LocalDate)
+            .map_err(|_| invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weekdays_are_parsed_by_name() {
+        let weekdays = Weekdays::try_from("sat, Sunday".to_owned()).unwrap();
+        assert!(weekdays.contains(Weekday::Saturday));
+        assert!(weekdays.contains(Weekday::Sunday));
+        assert!(!weekdays.contains(Weekday::Monday));
+
+        assert!(Weekdays::try_from("sun,someday".to_owned()).is_err());
+    }
+
+    #[test]
+    fn hour_ranges_wrap_past_midnight() {
+        let night = HourRange::try_from("22-04".to_owned()).unwrap();
+        let covered: Vec<u8> = (0..24).filter(|hour| night.contains(*hour)).collect();
+        assert_eq!(covered, [0, 1, 2, 3, 22, 23]);
+
+        let office = HourRange::try_from("9-17".to_owned()).unwrap();
+        assert!(office.contains(9) && office.contains(16));
+        assert!(!office.contains(17) && !office.contains(8));
+
+        for invalid in ["22", "5-5", "24-1", "a-b"] {
+            assert!(
+                HourRange::try_from(invalid.to_owned()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+}
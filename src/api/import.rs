@@ -0,0 +1,165 @@
+use super::{rejection, validate};
+use crate::storage::{Document, DocumentIdentifier, UserStorage};
+use axum::{extract::Query, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tracing::warn;
+
+// Imports carry many documents at once, so the per-document body limit does not apply
+pub const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ImportedDocument {
+    identifier: DocumentIdentifier,
+    contents: String,
+    title: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportFailure {
+    identifier: DocumentIdentifier,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ImportSummary {
+    imported: usize,
+    failed: Vec<ImportFailure>,
+}
+
+/// Writes every document it can instead of aborting on the first failure
+pub async fn import(
+    Query(query): Query<ImportQuery>,
+    storage: UserStorage,
+    Json(documents): Json<Vec<ImportedDocument>>,
+) -> Json<ImportSummary> {
+    let mut summary = ImportSummary {
+        imported: 0,
+        failed: Vec::new(),
+    };
+
+    for document in documents {
+        let identifier = document.identifier;
+
+        match import_one(&storage, document, query.overwrite).await {
+            Ok(()) => summary.imported += 1,
+            Err(reason) => summary.failed.push(ImportFailure { identifier, reason }),
+        }
+    }
+
+    Json(summary)
+}
+
+async fn import_one(
+    storage: &UserStorage,
+    ImportedDocument {
+        identifier,
+        contents,
+        title,
+    }: ImportedDocument,
+    overwrite: bool,
+) -> Result<(), &'static str> {
+    let reason = |status| match status {
+        StatusCode::PAYLOAD_TOO_LARGE => "too large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "binary",
+        _ => "invalid",
+    };
+
+    validate(storage, &contents).map_err(reason)?;
+
+    let internal_error = |e: io::Error| {
+        if let Some(status) = rejection(&e) {
+            return reason(status);
+        }
+
+        // Older than the configured maximum age, it would be purged right away
+        if e.kind() == io::ErrorKind::NotFound {
+            return "expired";
+        }
+
+        warn!("Failed to import document {identifier}: {e}");
+        "internal error"
+    };
+
+    let document = Document {
+        title,
+        ..Document::new(identifier, contents)
+    };
+
+    if overwrite {
+        return storage.write(document).await.map_err(internal_error);
+    }
+
+    match storage.write_new(document).await {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err("exists"),
+        result => result.map_err(internal_error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::tests::{body, TestApi},
+        storage::{tests::config, StorageConfig},
+    };
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+
+    async fn import(api: &TestApi, query: &str, documents: Value) -> Value {
+        let request = Request::post(format!("/api/import{query}"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(documents.to_string()))
+            .unwrap();
+
+        let response = api.send(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&body(response).await).unwrap()
+    }
+
+    async fn contents(api: &TestApi, identifier: u64) -> String {
+        let response = api.get(&format!("/api/document/{identifier}")).await;
+        String::from_utf8(body(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_without_aborting_the_import() {
+        let api = TestApi::new(StorageConfig {
+            max_document_bytes: 16,
+            ..config()
+        })
+        .await;
+        api.seed(&[(1, "Existing")]).await;
+
+        let documents = json!([
+            { "identifier": 1, "contents": "Replacement" },
+            { "identifier": 2, "contents": "New" },
+            { "identifier": 3, "contents": "Far too long for the limit" },
+        ]);
+
+        let summary = import(&api, "", documents.clone()).await;
+        assert_eq!(summary["imported"], 1);
+        assert_eq!(
+            summary["failed"],
+            json!([
+                { "identifier": 1, "reason": "exists" },
+                { "identifier": 3, "reason": "too large" },
+            ])
+        );
+        assert_eq!(contents(&api, 1).await, "Existing");
+        assert_eq!(contents(&api, 2).await, "New");
+
+        let summary = import(&api, "?overwrite=true", documents).await;
+        assert_eq!(summary["imported"], 2);
+        assert_eq!(summary["failed"].as_array().unwrap().len(), 1);
+        assert_eq!(contents(&api, 1).await, "Replacement");
+    }
+}
@@ -0,0 +1,160 @@
+use crate::storage::{Document, DocumentIdentifier};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, OriginalUri},
+    http::{header::ACCEPT, header::CONTENT_TYPE, request::Parts},
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use url::{Position, Url};
+
+pub const JSON_API: &str = "application/vnd.api+json";
+const RESOURCE_TYPE: &str = "document";
+
+/// Response format negotiated through the `Accept` header
+pub enum Representation {
+    Plain,
+    JsonApi { self_link: String },
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Representation
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accepts_json_api = parts
+            .headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .any(|h| h.contains(JSON_API));
+
+        if !accepts_json_api {
+            return Ok(Self::Plain);
+        }
+
+        // The original URI still contains the prefixes of nested routers
+        let self_link = parts
+            .extensions
+            .get::<OriginalUri>()
+            .map_or_else(|| parts.uri.to_string(), |uri| uri.0.to_string());
+
+        Ok(Self::JsonApi { self_link })
+    }
+}
+
+fn resource(document: &Document) -> Value {
+    let mut attributes = serde_json::to_value(document).expect("documents serialize to JSON");
+
+    if let Some(attributes) = attributes.as_object_mut() {
+        attributes.remove("identifier");
+    }
+
+    json!({
+        "type": RESOURCE_TYPE,
+        "id": document.identifier.to_string(),
+        "attributes": attributes,
+    })
+}
+
+pub fn collection(
+    documents: &[Document],
+    total: usize,
+    self_link: &str,
+    next_before: Option<DocumentIdentifier>,
+) -> Response {
+    let mut links = json!({ "self": self_link });
+
+    if let Some(next_before) = next_before {
+        links["next"] = with_query(self_link, "before", &next_before.to_string()).into();
+    }
+
+    respond(json!({
+        "data": documents.iter().map(resource).collect::<Vec<_>>(),
+        "links": links,
+        "meta": { "total": total },
+    }))
+}
+
+// Replaces a single query parameter of an origin-relative link
+fn with_query(link: &str, key: &str, value: &str) -> String {
+    let base = Url::parse("http://localhost").expect("base URL is valid");
+    let Ok(mut url) = base.join(link) else {
+        return link.to_owned();
+    };
+
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != key)
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(key, value);
+
+    url[Position::BeforePath..].to_owned()
+}
+
+pub fn single(document: &Document, self_link: &str) -> Response {
+    respond(json!({
+        "data": resource(document),
+        "links": { "self": self_link },
+    }))
+}
+
+fn respond(body: Value) -> Response {
+    ([(CONTENT_TYPE, JSON_API)], body.to_string()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::tests::{body, TestApi},
+        storage::tests::config,
+    };
+    use axum::{body::Body, http::Request};
+
+    async fn get(api: &TestApi, uri: &str) -> Value {
+        let request = Request::get(uri)
+            .header(ACCEPT, JSON_API)
+            .body(Body::empty())
+            .unwrap();
+        let response = api.send(request).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], JSON_API);
+
+        serde_json::from_slice(&body(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn listing_is_a_paginated_collection() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "first"), (2, "second"), (3, "third")]).await;
+
+        let page = get(&api, "/api/document?limit=2").await;
+        assert_eq!(page["meta"], json!({ "total": 3 }));
+        assert_eq!(page["links"]["self"], "/api/document?limit=2");
+        assert_eq!(page["links"]["next"], "/api/document?limit=2&before=2");
+
+        let data = page["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["type"], RESOURCE_TYPE);
+        assert_eq!(data[0]["id"], "3");
+        assert_eq!(data[0]["attributes"]["contents"], "third");
+        assert!(data[0]["attributes"].get("identifier").is_none());
+
+        let last = get(&api, page["links"]["next"].as_str().unwrap()).await;
+        assert_eq!(last["data"][0]["id"], "1");
+        assert!(last["links"].get("next").is_none());
+
+        let single = get(&api, "/api/document/2").await;
+        assert_eq!(single["data"]["id"], "2");
+        assert_eq!(single["links"]["self"], "/api/document/2");
+    }
+}
@@ -1,64 +1,1186 @@
-use crate::storage::{Document, DocumentIdentifier, UserStorage};
+use crate::{
+    markdown,
+    storage::{
+        sha256_hex, truncate_at_char_boundary, DecodedContents, Document, DocumentIdentifier,
+        PatchOperation, UserStorage, TRUNCATE_LEN,
+    },
+};
 use axum::{
     body::Body,
-    extract::Path,
-    http::StatusCode,
-    routing::{get, put},
-    Json, Router,
+    extract::{BodyStream, DefaultBodyLimit, Path, Query},
+    http::{
+        header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Extension, Json, Router,
 };
-use tokio::io::ErrorKind;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::Reverse;
+use time::format_description::well_known::Rfc3339;
+use tokio::io::{self, ErrorKind};
 use tracing::warn;
 
+mod config;
+mod export;
+mod filter;
+mod import;
+mod jsonapi;
+mod random;
+pub mod share;
+mod streak;
+mod summary;
+mod tags;
+mod tasks;
+mod templates;
+pub use jsonapi::Representation;
+
+pub const X_SOURCE_ENCODING: HeaderName = HeaderName::from_static("x-source-encoding");
+pub const X_CONTENT_SHA256: HeaderName = HeaderName::from_static("x-content-sha256");
+pub const X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+pub const X_DOCUMENT_DATE: HeaderName = HeaderName::from_static("x-document-date");
+pub const X_NEXT_BEFORE: HeaderName = HeaderName::from_static("x-next-before");
+pub const X_DOCUMENT_WEEKDAY: HeaderName = HeaderName::from_static("x-document-weekday");
+pub const X_DOCUMENT_CREATED: HeaderName = HeaderName::from_static("x-document-created");
+pub const X_DOCUMENT_MODIFIED: HeaderName = HeaderName::from_static("x-document-modified");
+pub const X_SIZE_WARNING: HeaderName = HeaderName::from_static("x-size-warning");
+// Appends carrying it end up in sequence order, see `UserStorage::append_in_sequence`
+pub const X_APPEND_SEQUENCE: HeaderName = HeaderName::from_static("x-append-sequence");
+
+// Endpoints that can be restricted to individual users through feature flags
+pub const FEATURE_STREAM: &str = "stream";
+pub const FEATURE_HTML_EXPORT: &str = "html_export";
+pub const FEATURE_TASKS: &str = "tasks";
+pub const GATEABLE_FEATURES: [&str; 3] = [FEATURE_STREAM, FEATURE_HTML_EXPORT, FEATURE_TASKS];
+
 pub fn router() -> Router<(), Body> {
     Router::new()
-        .route("/document", get(entries))
+        .route("/document", get(entries).post(create))
         .route("/document/:identifier", get(read))
-        .route("/document/:identifier", put(write))
+        .route("/document/:identifier", put(write).patch(patch))
+        .route(
+            "/document/:identifier/export.html",
+            get(export::export_html),
+        )
+        .route("/document/:identifier/stream", post(append_stream))
+        .route("/document/:identifier/share", post(share::share))
+        .route("/shared/:token", get(share::shared))
+        .route("/export", get(export::export_zip))
+        .route(
+            "/import",
+            post(import::import).layer(DefaultBodyLimit::max(import::MAX_IMPORT_BYTES)),
+        )
+        .route("/scratch", get(read_scratch).put(write_scratch))
+        .route("/random", get(random::random))
+        .route("/summary", get(summary::summary))
+        .route("/streak", get(streak::streak))
+        .route("/tags/:tag", get(tags::tagged))
+        .route("/tasks", get(tasks::tasks))
+        .route("/config", get(config::config))
+        .route("/templates", get(templates::list))
+        .route("/templates/:name", get(templates::get))
+        .layer(Extension(summary::WordCountCache::default()))
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingSort {
+    #[default]
+    Identifier,
+    Size,
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    #[default]
+    Markdown,
+    Plain,
 }
 
-async fn entries(storage: UserStorage) -> Result<Json<Vec<Document>>, StatusCode> {
-    let documents = storage.entries().await.map_err(|e| {
+#[derive(Deserialize)]
+pub struct ListingQuery {
+    min_bytes: Option<u64>,
+    max_bytes: Option<u64>,
+
+    // Evaluated against the local creation time in the configured timezone, so the local
+    // hour follows DST transitions within the date range. Both dates are inclusive.
+    weekday: Option<filter::Weekdays>,
+    hour_range: Option<filter::HourRange>,
+    from: Option<filter::LocalDate>,
+    to: Option<filter::LocalDate>,
+
+    // Length of the content previews in bytes
+    preview: Option<usize>,
+    #[serde(default)]
+    preview_format: PreviewFormat,
+
+    #[serde(default)]
+    sort: ListingSort,
+    #[serde(default)]
+    order: ListingOrder,
+
+    // Paging cursor, only documents created before this identifier are listed
+    before: Option<DocumentIdentifier>,
+    limit: Option<usize>,
+}
+
+pub(crate) async fn entries(
+    Query(query): Query<ListingQuery>,
+    representation: Representation,
+    storage: UserStorage,
+) -> Result<Response, StatusCode> {
+    let internal_error = |e| {
         warn!("Failed to list documents: {e}");
         StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    };
+
+    let mut listing = storage.list().await.map_err(internal_error)?;
+    let timezone = storage.config().timezone;
+
+    listing.retain(|m| {
+        let size_matches = query.min_bytes.is_none_or(|min| m.size >= min)
+            && query.max_bytes.is_none_or(|max| m.size <= max);
+
+        if query.weekday.is_none()
+            && query.hour_range.is_none()
+            && query.from.is_none()
+            && query.to.is_none()
+        {
+            return size_matches;
+        }
+
+        let Some(local) = m.identifier.local_datetime(timezone) else {
+            return false;
+        };
+
+        size_matches
+            && query
+                .weekday
+                .as_ref()
+                .is_none_or(|weekdays| weekdays.contains(local.weekday()))
+            && query
+                .hour_range
+                .is_none_or(|range| range.contains(local.hour()))
+            && query.from.is_none_or(|from| local.date() >= from.date())
+            && query.to.is_none_or(|to| local.date() <= to.date())
+    });
+
+    // The listing is newest first, a stable sort keeps that as the tie-breaker
+    if query.sort == ListingSort::Size {
+        listing.sort_by_key(|m| Reverse(m.size));
+    }
+
+    if query.order == ListingOrder::Asc {
+        listing.reverse();
+    }
+
+    // Clients can tell from the total whether the listing has been cut off
+    let total_count = listing.len();
+
+    if let Some(before) = query.before {
+        listing.retain(|m| m.identifier < before);
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(usize::MAX)
+        .min(storage.config().max_listing);
+
+    // The cursor only pages through the default newest-first order
+    let next_before = match limit.checked_sub(1) {
+        Some(last)
+            if listing.len() > limit
+                && query.sort == ListingSort::Identifier
+                && query.order == ListingOrder::Desc =>
+        {
+            Some(listing[last].identifier)
+        }
+        _ => None,
+    };
+
+    listing.truncate(limit);
+
+    let preview_len = query
+        .preview
+        .unwrap_or(TRUNCATE_LEN)
+        .min(storage.config().max_preview_len);
+
+    let documents = match query.preview_format {
+        PreviewFormat::Markdown => storage
+            .entries(&listing, Some(preview_len))
+            .await
+            .map_err(internal_error)?,
+        // Markup has to be stripped from the full document so the preview gets the full length
+        PreviewFormat::Plain => storage
+            .entries(&listing, None)
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .map(|mut document| {
+                document.contents = markdown::to_plain_text(&document.contents);
+                truncate_at_char_boundary(&mut document.contents, preview_len);
+                document
+            })
+            .collect(),
+    };
 
-    Ok(Json(documents))
+    let mut response = match representation {
+        Representation::Plain => ([(X_TOTAL_COUNT, total_count)], Json(documents)).into_response(),
+        Representation::JsonApi { self_link } => {
+            jsonapi::collection(&documents, total_count, &self_link, next_before)
+        }
+    };
+
+    if let Some(next_before) = next_before {
+        response.headers_mut().insert(
+            X_NEXT_BEFORE,
+            HeaderValue::from_str(&next_before.to_string())
+                .expect("identifiers are valid header values"),
+        );
+    }
+
+    Ok(response)
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadFormat {
+    #[default]
+    Raw,
+    Lines,
+}
+
+#[derive(Deserialize)]
+pub struct ReadQuery {
+    #[serde(default)]
+    format: ReadFormat,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    n: usize,
+    text: &'a str,
 }
 
-async fn read(
+pub(crate) async fn read(
     Path(identifier): Path<DocumentIdentifier>,
+    Query(query): Query<ReadQuery>,
+    representation: Representation,
+    headers: HeaderMap,
     storage: UserStorage,
-) -> Result<String, StatusCode> {
-    let document = storage
-        .read(identifier, false)
+) -> Result<Response, StatusCode> {
+    let not_found_or_internal = |e: io::Error| match e.kind() {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => {
+            warn!("Failed to read document: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    if let Representation::JsonApi { self_link } = representation {
+        let document = storage
+            .read(identifier, None)
+            .await
+            .map_err(not_found_or_internal)?;
+
+        return Ok(jsonapi::single(&document, &self_link));
+    }
+
+    let contents = storage
+        .read_decoded(identifier)
         .await
-        .map_err(|e| match e.kind() {
-            ErrorKind::NotFound => StatusCode::NOT_FOUND,
-            _ => {
-                warn!("Failed to read document: {e}");
-                StatusCode::INTERNAL_SERVER_ERROR
+        .map_err(not_found_or_internal)?;
+
+    // Weak since the line representation is derived from, but not identical to, the contents
+    let etag = match query.format {
+        ReadFormat::Raw => format!("W/\"{}\"", contents.sha256()),
+        ReadFormat::Lines => format!("W/\"{}-lines\"", contents.sha256()),
+    };
+    let etag = HeaderValue::from_str(&etag).expect("hex digests are valid header values");
+
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+
+    let size_warning = storage.config().size_warning(contents.size());
+
+    let mut response = match (query.format, contents) {
+        (ReadFormat::Raw, contents) => decoded_response(contents),
+        // A trailing newline terminates the last line instead of starting an empty one
+        (ReadFormat::Lines, DecodedContents::Text { contents, .. }) => Json(
+            contents
+                .split_terminator('\n')
+                .enumerate()
+                .map(|(i, text)| Line { n: i + 1, text })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        (ReadFormat::Lines, DecodedContents::Binary(_)) => {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+    };
+
+    if let Some(local) = identifier.local_datetime(storage.config().timezone) {
+        let date = local.date().to_string();
+        let headers = response.headers_mut();
+        headers.insert(
+            X_DOCUMENT_DATE,
+            HeaderValue::from_str(&date).expect("dates are valid header values"),
+        );
+        headers.insert(
+            X_DOCUMENT_WEEKDAY,
+            u16::from(local.weekday().number_from_monday()).into(),
+        );
+
+        if let Ok(created) = local.format(&Rfc3339) {
+            headers.insert(
+                X_DOCUMENT_CREATED,
+                HeaderValue::from_str(&created).expect("timestamps are valid header values"),
+            );
+        }
+    }
+
+    if let Some(modified) = storage.modified_at(identifier).await {
+        response.headers_mut().insert(
+            X_DOCUMENT_MODIFIED,
+            HeaderValue::from_str(&modified).expect("timestamps are valid header values"),
+        );
+    }
+
+    if let Some(size_warning) = size_warning {
+        response.headers_mut().insert(
+            X_SIZE_WARNING,
+            HeaderValue::from_static(if size_warning { "true" } else { "false" }),
+        );
+    }
+
+    response.headers_mut().insert(ETAG, etag);
+
+    Ok(response)
+}
+
+/// Weak comparison against every tag listed in `If-None-Match`, as required by RFC 9110
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let Ok(etag) = etag.to_str().map(opaque) else {
+        return false;
+    };
+
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+async fn read_scratch(storage: UserStorage) -> Result<Response, StatusCode> {
+    let contents = storage.read_scratch().await.map_err(|e| match e.kind() {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => {
+            warn!("Failed to read scratch document: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    Ok(decoded_response(contents))
+}
+
+pub(crate) fn decoded_response(contents: DecodedContents) -> Response {
+    match contents {
+        DecodedContents::Text {
+            contents,
+            source_encoding,
+        } => {
+            let sha256 = sha256_hex(contents.as_bytes());
+
+            match source_encoding {
+                None => ([(X_CONTENT_SHA256, sha256)], contents).into_response(),
+                Some(encoding) => (
+                    [
+                        (X_CONTENT_SHA256, sha256),
+                        (X_SOURCE_ENCODING, encoding.name().to_owned()),
+                    ],
+                    contents,
+                )
+                    .into_response(),
             }
-        })?;
+        }
+        DecodedContents::Binary(bytes) => (
+            [
+                (X_CONTENT_SHA256, sha256_hex(&bytes)),
+                (CONTENT_TYPE, "application/octet-stream".to_owned()),
+            ],
+            bytes,
+        )
+            .into_response(),
+    }
+}
 
-    Ok(document.contents)
+#[derive(Deserialize)]
+pub struct WriteQuery {
+    // Replaces the front matter of the body, an empty title removes it
+    title: Option<String>,
 }
 
 async fn write(
     Path(identifier): Path<DocumentIdentifier>,
+    Query(query): Query<WriteQuery>,
     storage: UserStorage,
     contents: String,
 ) -> StatusCode {
-    match storage
-        .write(Document {
-            identifier,
-            contents,
-        })
-        .await
-    {
+    if let Err(status) = validate(&storage, &contents) {
+        return status;
+    }
+
+    let document = Document {
+        title: query.title,
+        ..Document::new(identifier, contents)
+    };
+
+    match storage.write(document).await {
         Ok(_) => StatusCode::NO_CONTENT,
-        Err(err) => {
+        // Past the maximum age
+        Err(err) if err.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        // The title may push an otherwise acceptable body over the limit
+        Err(err) => rejection(&err).unwrap_or_else(|| {
             warn!("Failed to write document: {err}");
             StatusCode::INTERNAL_SERVER_ERROR
+        }),
+    }
+}
+
+// Plain text bodies are appended, JSON ones pick the operation. Appends with a sequence number
+// are ordered by it among each other, even if they arrive out of order.
+async fn patch(
+    Path(identifier): Path<DocumentIdentifier>,
+    headers: HeaderMap,
+    storage: UserStorage,
+    body: String,
+) -> StatusCode {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let operation = if is_json {
+        match serde_json::from_str(&body) {
+            Ok(operation) => operation,
+            Err(_) => return StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    } else {
+        PatchOperation::Append(body)
+    };
+
+    let sequence = match headers.get(X_APPEND_SEQUENCE).map(|value| {
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+    }) {
+        None => None,
+        Some(Some(sequence)) => Some(sequence),
+        Some(None) => return StatusCode::BAD_REQUEST,
+    };
+
+    if let Err(status) = validate(&storage, operation.text()) {
+        return status;
+    }
+
+    let result = match (operation, sequence) {
+        (PatchOperation::Append(text), Some(sequence)) => {
+            storage.append_in_sequence(identifier, text, sequence).await
+        }
+        // Only appends can be ordered
+        (PatchOperation::Prepend(_), Some(_)) => return StatusCode::UNPROCESSABLE_ENTITY,
+        (operation, None) => storage.patch(identifier, operation).await,
+    };
+
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => match err.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidData => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => rejection(&err).unwrap_or_else(|| {
+                warn!("Failed to patch document: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }),
+        },
+    }
+}
+
+async fn append_stream(
+    Path(identifier): Path<DocumentIdentifier>,
+    storage: UserStorage,
+    body: BodyStream,
+) -> StatusCode {
+    if let Err(status) = require_feature(&storage, FEATURE_STREAM).await {
+        return status;
+    }
+
+    let chunks = body.map(|chunk| chunk.map_err(io::Error::other));
+
+    // Nothing is appended unless the stream completes and passes all checks
+    match storage.append_stream(identifier, chunks).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) if err.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        Err(err) if err.kind() == ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+        Err(err) => rejection(&err).unwrap_or_else(|| {
+            warn!("Failed to append to document: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateQuery {
+    template: Option<String>,
+    title: Option<String>,
+}
+
+async fn create(
+    Query(query): Query<CreateQuery>,
+    storage: UserStorage,
+    contents: String,
+) -> Result<Response, StatusCode> {
+    // The request body, if any, follows the template
+    let contents = match query.template {
+        Some(name) => templates::render(storage.config(), &name).await? + &contents,
+        None => contents,
+    };
+
+    let contents = match query.title {
+        Some(title) => markdown::set_title(&contents, &title),
+        None => contents,
+    };
+
+    // Oversized entries become several consecutive ones instead of being rejected
+    let chunks = match storage.config().autosplit_bytes {
+        Some(max_bytes) if contents.len() > max_bytes => {
+            markdown::split_at_paragraphs(&contents, max_bytes)
         }
+        _ => vec![contents],
+    };
+
+    // Nothing is created unless every chunk is acceptable
+    for chunk in &chunks {
+        validate(&storage, chunk)?;
+    }
+
+    let mut identifiers = Vec::with_capacity(chunks.len());
+
+    for chunk in chunks {
+        let identifier = storage.create(chunk).await.map_err(|e| {
+            rejection(&e).unwrap_or_else(|| {
+                warn!("Failed to create document: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+        })?;
+
+        identifiers.push(identifier);
+    }
+
+    let identifier = identifiers[0];
+
+    Ok((
+        StatusCode::CREATED,
+        [(LOCATION, format!("/api/document/{identifier}"))],
+        Json(json!({ "identifier": identifier, "identifiers": identifiers })),
+    )
+        .into_response())
+}
+
+async fn write_scratch(storage: UserStorage, contents: String) -> StatusCode {
+    if let Err(status) = validate(&storage, &contents) {
+        return status;
+    }
+
+    match storage.write_scratch(contents).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            warn!("Failed to write scratch document: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+// Gated features are hidden entirely from users without the flag
+async fn require_feature(storage: &UserStorage, feature: &str) -> Result<(), StatusCode> {
+    match storage.has_feature(feature).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Failed to read feature flags: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// Checks the storage applies to every write anyway, run upfront so nothing is touched on failure
+fn validate(storage: &UserStorage, contents: &str) -> Result<(), StatusCode> {
+    storage
+        .config()
+        .validate(contents)
+        .map_err(|e| rejection(&e).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Status for writes the storage refused because of their contents
+pub(crate) fn rejection(err: &io::Error) -> Option<StatusCode> {
+    match err.kind() {
+        ErrorKind::FileTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+        ErrorKind::InvalidInput => Some(StatusCode::BAD_REQUEST),
+        ErrorKind::Unsupported => Some(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        auth::oidc,
+        storage::{tests::config, StorageConfig, StorageState},
+    };
+    use axum::{body::HttpBody, http::Request};
+    use sha2::{Digest, Sha256};
+    use std::time::{Duration, SystemTime};
+    use time::OffsetDateTime;
+    use tower::ServiceExt;
+
+    /// The API as seen by `alice`, who is authenticated through the mock identity provider
+    pub(crate) struct TestApi {
+        auth_client: oidc::AuthClient,
+        pub(crate) config: StorageConfig,
+        pub(crate) state: StorageState,
+    }
+
+    impl TestApi {
+        pub(crate) async fn new(config: StorageConfig) -> Self {
+            let idp = oidc::tests::mock_idp().await;
+
+            Self {
+                auth_client: oidc::tests::client(&idp).await,
+                config,
+                state: StorageState::default(),
+            }
+        }
+
+        pub(crate) fn storage(&self) -> UserStorage {
+            UserStorage::new(self.config.clone(), self.state.clone(), "alice")
+        }
+
+        pub(crate) async fn send(&self, request: Request<Body>) -> Response {
+            let app = Router::new()
+                .nest("/api", router())
+                .layer(Extension(self.auth_client.clone()))
+                .layer(Extension(self.config.clone()))
+                .layer(Extension(self.state.clone()))
+                .layer(Extension(share::ShareConfig { secret: None }));
+
+            let (mut parts, body) = request.into_parts();
+            parts
+                .headers
+                .insert("cookie", HeaderValue::from_static("refresh=r-alice"));
+
+            app.oneshot(Request::from_parts(parts, body)).await.unwrap()
+        }
+
+        pub(crate) async fn post(&self, uri: &str, body: &str) -> Response {
+            self.send(
+                Request::post(uri)
+                    .body(Body::from(body.to_owned()))
+                    .unwrap(),
+            )
+            .await
+        }
+
+        pub(crate) async fn put(&self, uri: &str, body: &str) -> Response {
+            self.send(Request::put(uri).body(Body::from(body.to_owned())).unwrap())
+                .await
+        }
+
+        pub(crate) async fn get(&self, uri: &str) -> Response {
+            self.send(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+        }
+
+        /// Body of a successful `GET` as JSON
+        pub(crate) async fn json(&self, uri: &str) -> serde_json::Value {
+            let response = self.get(uri).await;
+            assert_eq!(response.status(), StatusCode::OK, "GET {uri}");
+            serde_json::from_slice(&body(response).await).unwrap()
+        }
+
+        pub(crate) async fn seed(&self, documents: &[(u64, &str)]) {
+            let storage = self.storage();
+
+            for (identifier, contents) in documents {
+                storage
+                    .write(crate::storage::tests::document(*identifier, contents))
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    impl Drop for TestApi {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.config.location);
+        }
+    }
+
+    pub(crate) async fn body(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+
+        bytes
+    }
+
+    fn identifiers(listing: &serde_json::Value) -> Vec<u64> {
+        listing
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|document| document["identifier"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn listing_filters_and_sorts_by_size() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "12345"), (2, "1"), (3, "123"), (4, "1234567")])
+            .await;
+
+        let listing = api.json("/api/document?min_bytes=2&max_bytes=6").await;
+        assert_eq!(identifiers(&listing), [3, 1]);
+
+        let listing = api.json("/api/document?sort=size").await;
+        assert_eq!(identifiers(&listing), [4, 1, 3, 2]);
+
+        let listing = api
+            .json("/api/document?sort=size&order=asc&min_bytes=2")
+            .await;
+        assert_eq!(identifiers(&listing), [3, 1, 4]);
+    }
+
+    #[tokio::test]
+    async fn checksum_matches_the_returned_bytes() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "Grüße\n")]).await;
+
+        let response = api.get("/api/document/1").await;
+        let header = response.headers()[X_CONTENT_SHA256]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let expected = hex::encode(Sha256::digest(body(response).await));
+        assert_eq!(header, expected);
+
+        let listing = api.json("/api/document").await;
+        assert_eq!(listing[0]["sha256"], expected);
+    }
+
+    #[tokio::test]
+    async fn listing_is_capped_regardless_of_the_requested_limit() {
+        let api = TestApi::new(StorageConfig {
+            max_listing: 3,
+            ..config()
+        })
+        .await;
+        api.seed(&[(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e")])
+            .await;
+
+        for uri in ["/api/document", "/api/document?limit=100"] {
+            let response = api.get(uri).await;
+            assert_eq!(response.headers()[X_TOTAL_COUNT], "5");
+
+            let listing = serde_json::from_slice(&body(response).await).unwrap();
+            assert_eq!(identifiers(&listing), [5, 4, 3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn scratch_round_trips_outside_the_listing() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "entry")]).await;
+
+        let response = api.put("/api/scratch", "jotted down").await;
+        assert!(response.status().is_success());
+
+        let response = api.get("/api/scratch").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, b"jotted down");
+
+        let listing = api.json("/api/document").await;
+        assert_eq!(identifiers(&listing), [1]);
+    }
+
+    #[tokio::test]
+    async fn local_dates_follow_dst_transitions() {
+        let api = TestApi::new(StorageConfig {
+            timezone: time_tz::timezones::get_by_name("Europe/Berlin").unwrap(),
+            ..config()
+        })
+        .await;
+
+        // Both at 22:30 UTC, which is only the next local day while summer time is in effect
+        let spring = 1_711_924_200_000; // 2024-03-31, the day summer time starts
+        let autumn = 1_729_981_800_000; // 2024-10-26, the day before it ends
+        api.seed(&[(spring, "spring"), (autumn, "autumn")]).await;
+
+        let listing = api.json("/api/document").await;
+        assert_eq!(listing[0]["date"], "2024-10-27");
+        assert_eq!(listing[0]["weekday"], 7);
+        assert_eq!(listing[1]["date"], "2024-04-01");
+        assert_eq!(listing[1]["weekday"], 1);
+
+        let response = api.get(&format!("/api/document/{spring}")).await;
+        assert_eq!(response.headers()[X_DOCUMENT_DATE], "2024-04-01");
+        assert_eq!(response.headers()[X_DOCUMENT_WEEKDAY], "1");
+    }
+
+    #[tokio::test]
+    async fn previews_respect_the_requested_length_and_cap() {
+        let api = TestApi::new(StorageConfig {
+            max_preview_len: 6,
+            ..config()
+        })
+        .await;
+        api.seed(&[(1, "Grüße aus Köln")]).await;
+
+        let preview = |listing: serde_json::Value| listing[0]["contents"].clone();
+
+        assert_eq!(preview(api.json("/api/document?preview=4").await), "Grü");
+        // Cutting "ß" in half would leave invalid UTF-8
+        assert_eq!(preview(api.json("/api/document?preview=5").await), "Grü");
+        assert_eq!(preview(api.json("/api/document?preview=100").await), "Grüß");
+        assert_eq!(preview(api.json("/api/document").await), "Grüß");
+    }
+
+    #[tokio::test]
+    async fn overwrites_advance_modified_but_not_created() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1_700_000_000_000, "First draft")]).await;
+
+        // Backdated so the overwrite is guaranteed to be later even on coarse filesystem clocks
+        let identifier = api.storage().list().await.unwrap()[0].identifier;
+        let path = api.storage().doc_path(identifier).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        let timestamps = |listing: serde_json::Value| {
+            let document = &listing[0];
+            let parse = |field: &str| {
+                OffsetDateTime::parse(document[field].as_str().unwrap(), &Rfc3339).unwrap()
+            };
+            (parse("created_at"), parse("modified_at"))
+        };
+
+        let (created, modified) = timestamps(api.json("/api/document").await);
+        assert_eq!(created.unix_timestamp(), 1_700_000_000);
+
+        let response = api.put("/api/document/1700000000000", "Final draft").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (created_after, modified_after) = timestamps(api.json("/api/document").await);
+        assert_eq!(created_after, created);
+        assert!(modified_after > modified);
+    }
+
+    #[tokio::test]
+    async fn size_warning_flips_past_the_threshold() {
+        let api = TestApi::new(StorageConfig {
+            large_document_bytes: Some(8),
+            ..config()
+        })
+        .await;
+        let warning = |listing: serde_json::Value| listing[0]["size_warning"].clone();
+
+        api.seed(&[(1, "12345678")]).await;
+        assert_eq!(warning(api.json("/api/document").await), false);
+        let response = api.get("/api/document/1").await;
+        assert_eq!(response.headers()[X_SIZE_WARNING], "false");
+
+        api.seed(&[(1, "123456789")]).await;
+        assert_eq!(warning(api.json("/api/document").await), true);
+        let response = api.get("/api/document/1").await;
+        assert_eq!(response.headers()[X_SIZE_WARNING], "true");
+    }
+
+    #[tokio::test]
+    async fn oversized_creates_are_split_at_paragraphs() {
+        let api = TestApi::new(StorageConfig {
+            autosplit_bytes: Some(20),
+            ..config()
+        })
+        .await;
+
+        let response = api
+            .post(
+                "/api/document",
+                "First paragraph.\n\nSecond paragraph,\nstill second.\n\nThird.\n",
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let created: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        let identifiers: Vec<u64> = created["identifiers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|identifier| identifier.as_u64().unwrap())
+            .collect();
+        assert!(identifiers.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let mut chunks = Vec::new();
+        for identifier in identifiers {
+            let response = api.get(&format!("/api/document/{identifier}")).await;
+            chunks.push(String::from_utf8(body(response).await).unwrap());
+        }
+
+        // The second paragraph alone exceeds the limit but is kept whole
+        assert_eq!(
+            chunks,
+            [
+                "First paragraph.\n",
+                "Second paragraph,\nstill second.\n",
+                "Third.\n"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_over_the_size_limit_are_rejected() {
+        let api = TestApi::new(StorageConfig {
+            max_document_bytes: 8,
+            ..config()
+        })
+        .await;
+
+        // Counted in UTF-8 bytes, not characters
+        let response = api.put("/api/document/1", "üüüü").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = api.put("/api/document/2", "üüüüa").await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(identifiers(&api.json("/api/document").await), [1]);
+    }
+
+    #[tokio::test]
+    async fn sequenced_appends_are_applied_in_sequence_order() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "Log\n")]).await;
+
+        let append = |sequence: &str, text: &str| {
+            Request::patch("/api/document/1")
+                .header(X_APPEND_SEQUENCE, sequence)
+                .body(Body::from(text.to_owned()))
+                .unwrap()
+        };
+
+        for (sequence, text) in [("3", "third\n"), ("1", "first\n"), ("2", "second\n")] {
+            let response = api.send(append(sequence, text)).await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+
+        let response = api.get("/api/document/1").await;
+        assert_eq!(body(response).await, b"Log\nfirst\nsecond\nthird\n");
+
+        let response = api.send(append("soon", "never\n")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn too_many_tags_are_rejected_by_default() {
+        let api = TestApi::new(StorageConfig {
+            max_tags_per_document: 2,
+            ..config()
+        })
+        .await;
+
+        let response = api
+            .put("/api/document/1", "---\ntags: [a, b]\n---\nOk\n")
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = api
+            .put("/api/document/2", "---\ntags: [a, b, c]\n---\nSpam\n")
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(identifiers(&api.json("/api/document").await), [1]);
+    }
+
+    #[tokio::test]
+    async fn too_many_tags_can_be_truncated() {
+        let api = TestApi::new(StorageConfig {
+            max_tags_per_document: 2,
+            tag_limit_mode: crate::storage::TagLimitMode::Truncate,
+            ..config()
+        })
+        .await;
+
+        let response = api
+            .put("/api/document/1", "---\ntags: [a, b]\n---\nOk\n")
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = api
+            .put(
+                "/api/document/2",
+                "---\ntitle: Spam\ntags:\n- a\n- b\n- c\nmood: fine\n---\nBody\n",
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let listing = api.json("/api/document").await;
+        assert_eq!(identifiers(&listing), [2, 1]);
+        assert_eq!(listing[0]["tags"], serde_json::json!(["a", "b"]));
+        assert_eq!(listing[0]["title"], "Spam");
+        assert_eq!(listing[0]["contents"], "Body\n");
+        assert_eq!(listing[1]["tags"], serde_json::json!(["a", "b"]));
+
+        let response = api.get("/api/document/2").await;
+        let stored = String::from_utf8(body(response).await).unwrap();
+        assert!(stored.contains("mood: fine"));
+    }
+
+    #[tokio::test]
+    async fn binary_looking_writes_are_rejected() {
+        let api = TestApi::new(StorageConfig {
+            reject_binary_threshold: Some(0.1),
+            ..config()
+        })
+        .await;
+
+        let response = api
+            .put("/api/document/1", "Plain prose,\n\twith some whitespace.\n")
+            .await;
+        assert!(response.status().is_success());
+
+        let response = api
+            .put("/api/document/2", "\u{1}\u{2}\u{3}\u{4}\u{5}\u{6}ab")
+            .await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(identifiers(&api.json("/api/document").await), [1]);
+    }
+
+    #[tokio::test]
+    async fn listing_filters_by_weekday_and_hour() {
+        let api = TestApi::new(config()).await;
+        let sunday_night = 1_704_668_400_000; // 2024-01-07 23:00
+        let monday_night = 1_704_682_800_000; // 2024-01-08 03:00
+        let monday_noon = 1_704_715_200_000; // 2024-01-08 12:00
+        api.seed(&[(sunday_night, "a"), (monday_night, "b"), (monday_noon, "c")])
+            .await;
+
+        let listing = api.json("/api/document?weekday=sun").await;
+        assert_eq!(identifiers(&listing), [sunday_night]);
+
+        let listing = api.json("/api/document?hour_range=22-04").await;
+        assert_eq!(identifiers(&listing), [monday_night, sunday_night]);
+
+        let listing = api.json("/api/document?weekday=mon&hour_range=22-04").await;
+        assert_eq!(identifiers(&listing), [monday_night]);
+    }
+
+    #[tokio::test]
+    async fn listing_combines_local_hour_and_date_windows() {
+        let api = TestApi::new(StorageConfig {
+            timezone: time_tz::timezones::get_by_name("Europe/Berlin").unwrap(),
+            ..config()
+        })
+        .await;
+
+        // Summer time starts on 2024-03-31, moving the local hours of later entries by one
+        let first_day = 1_711_342_800_000; // 2024-03-25 06:00 local
+        let winter = 1_711_690_200_000; // 2024-03-29 06:30 local
+        let summer_early = 1_712_028_600_000; // 2024-04-02 05:30 local, 03:30 UTC
+        let summer_late = 1_712_046_600_000; // 2024-04-02 10:30 local, 08:30 UTC
+        let after = 1_712_728_800_000; // 2024-04-10 08:00 local
+        api.seed(&[
+            (first_day, "a"),
+            (winter, "b"),
+            (summer_early, "c"),
+            (summer_late, "d"),
+            (after, "e"),
+        ])
+        .await;
+
+        let listing = api
+            .json("/api/document?hour_range=5-9&from=2024-03-25&to=2024-04-05")
+            .await;
+        assert_eq!(identifiers(&listing), [summer_early, winter, first_day]);
+    }
+
+    #[tokio::test]
+    async fn plain_previews_have_markup_removed() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(
+            1,
+            "# Title\n\nSome **bold** [link](https://example.com) ünd more",
+        )])
+        .await;
+
+        let listing = api.json("/api/document").await;
+        assert!(listing[0]["contents"]
+            .as_str()
+            .unwrap()
+            .starts_with("# Title"));
+
+        let listing = api.json("/api/document?preview_format=plain").await;
+        assert_eq!(listing[0]["contents"], "Title\nSome bold link ünd more");
+
+        // Truncated after stripping, on a character boundary
+        let listing = api
+            .json("/api/document?preview_format=plain&preview=23")
+            .await;
+        assert_eq!(listing[0]["contents"], "Title\nSome bold link ü");
+        let listing = api
+            .json("/api/document?preview_format=plain&preview=22")
+            .await;
+        assert_eq!(listing[0]["contents"], "Title\nSome bold link ");
+    }
+
+    #[tokio::test]
+    async fn lines_are_numbered_from_one() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[(1, "first\n\nthird\n"), (2, "text\n\n")]).await;
+
+        let lines = api.json("/api/document/1?format=lines").await;
+        assert_eq!(
+            lines,
+            json!([
+                { "n": 1, "text": "first" },
+                { "n": 2, "text": "" },
+                { "n": 3, "text": "third" },
+            ])
+        );
+
+        // Only the final newline terminates a line, an empty line before it is kept
+        let lines = api.json("/api/document/2?format=lines").await;
+        assert_eq!(
+            lines,
+            json!([{ "n": 1, "text": "text" }, { "n": 2, "text": "" }])
+        );
     }
 }
@@ -0,0 +1,84 @@
+use super::{jsonapi, Representation};
+use crate::storage::{DocumentIdentifier, UserStorage};
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use tokio::io::ErrorKind;
+use tracing::warn;
+
+#[derive(Deserialize)]
+pub struct RandomQuery {
+    // Only documents created before this identifier are considered
+    before: Option<DocumentIdentifier>,
+}
+
+/// Picks a uniformly random document from the listing, so only the chosen one is read
+pub async fn random(
+    Query(query): Query<RandomQuery>,
+    representation: Representation,
+    storage: UserStorage,
+) -> Result<Response, StatusCode> {
+    let internal_error = |e| {
+        warn!("Failed to read random document: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let mut listing = storage.list().await.map_err(internal_error)?;
+
+    if let Some(before) = query.before {
+        listing.retain(|m| m.identifier < before);
+    }
+
+    let identifier = listing
+        .choose(&mut rand::thread_rng())
+        .ok_or(StatusCode::NOT_FOUND)?
+        .identifier;
+
+    let document = storage
+        .read(identifier, None)
+        .await
+        .map_err(|e| match e.kind() {
+            // Deleted since it was listed
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => internal_error(e),
+        })?;
+
+    Ok(match representation {
+        Representation::Plain => Json(document).into_response(),
+        Representation::JsonApi { self_link } => jsonapi::single(&document, &self_link),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::tests::TestApi, storage::tests::config};
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn random_picks_an_existing_entry_before_the_cursor() {
+        let api = TestApi::new(config()).await;
+
+        let response = api.get("/api/random").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        api.seed(&[(1, "one"), (2, "two"), (3, "three")]).await;
+
+        for _ in 0..20 {
+            let document = api.json("/api/random?before=3").await;
+            let expected = match document["identifier"].as_u64().unwrap() {
+                1 => "one",
+                2 => "two",
+                identifier => panic!("picked {identifier} despite the cursor"),
+            };
+            assert_eq!(document["contents"], expected);
+        }
+
+        let response = api.get("/api/random?before=1").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::storage::{Document, DocumentIdentifier, StorageConfig, StorageState, UserStorage};
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Extension, Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::ErrorKind;
+use tracing::warn;
+
+const DEFAULT_SHARE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+const MAX_SHARE_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Clone)]
+pub struct ShareConfig {
+    // Sharing is disabled entirely while no secret is configured. Changing it invalidates all
+    // links handed out so far.
+    pub secret: Option<Arc<[u8]>>,
+}
+
+#[derive(Deserialize)]
+pub struct ShareQuery {
+    // Validity of the link in seconds
+    expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SharedLink {
+    token: String,
+    expires_at: String,
+}
+
+// Everything needed to serve the document without a session, signed as a whole
+#[derive(Serialize, Deserialize)]
+struct SharePayload {
+    sub: String,
+    id: DocumentIdentifier,
+    exp: i64,
+}
+
+/// Hands out a token for `GET /api/shared/:token`, granting read access to a single document
+/// until it expires
+pub async fn share(
+    Path(identifier): Path<DocumentIdentifier>,
+    Query(query): Query<ShareQuery>,
+    Extension(config): Extension<ShareConfig>,
+    storage: UserStorage,
+) -> Result<Json<SharedLink>, StatusCode> {
+    let secret = config.secret.ok_or(StatusCode::NOT_FOUND)?;
+
+    match storage.exists(identifier).await {
+        Ok(true) => {}
+        Ok(false) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Failed to share document: {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    let ttl = query
+        .expires_in
+        .unwrap_or(DEFAULT_SHARE_TTL_SECS)
+        .clamp(1, MAX_SHARE_TTL_SECS);
+    let expires_at = OffsetDateTime::now_utc().unix_timestamp() + ttl;
+
+    let payload = SharePayload {
+        sub: storage.user_id().to_owned(),
+        id: identifier,
+        exp: expires_at,
+    };
+    let payload = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(&payload).expect("failed to serialize share payload"));
+    let signature = URL_SAFE_NO_PAD.encode(sign(&secret, &payload).finalize().into_bytes());
+
+    Ok(Json(SharedLink {
+        token: format!("{payload}.{signature}"),
+        expires_at: OffsetDateTime::from_unix_timestamp(expires_at)
+            .ok()
+            .and_then(|datetime| datetime.format(&Rfc3339).ok())
+            .unwrap_or_default(),
+    }))
+}
+
+/// Serves a shared document to anyone holding a valid token, no session required
+pub async fn shared(
+    Path(token): Path<String>,
+    Extension(config): Extension<ShareConfig>,
+    Extension(storage_config): Extension<StorageConfig>,
+    Extension(storage_state): Extension<StorageState>,
+) -> Result<Json<Document>, StatusCode> {
+    let secret = config.secret.ok_or(StatusCode::NOT_FOUND)?;
+    let payload = verify(&secret, &token).ok_or(StatusCode::FORBIDDEN)?;
+
+    if payload.exp <= OffsetDateTime::now_utc().unix_timestamp() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    UserStorage::new(storage_config, storage_state, &payload.sub)
+        .read(payload.id, None)
+        .await
+        .map(Json)
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => {
+                warn!("Failed to read shared document: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })
+}
+
+fn sign(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+// Only payloads carrying a valid signature are decoded, so their contents can be trusted
+fn verify(secret: &[u8], token: &str) -> Option<SharePayload> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    // Compares in constant time
+    sign(secret, payload).verify_slice(&signature).ok()?;
+
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{config, document};
+
+    const SECRET: &[u8] = b"share-secret";
+
+    fn token(secret: &[u8], payload: &SharePayload) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    async fn open(storage_config: &StorageConfig, token: String) -> Result<String, StatusCode> {
+        let config = ShareConfig {
+            secret: Some(SECRET.into()),
+        };
+
+        shared(
+            Path(token),
+            Extension(config),
+            Extension(storage_config.clone()),
+            Extension(StorageState::default()),
+        )
+        .await
+        .map(|Json(document)| document.contents)
+    }
+
+    #[tokio::test]
+    async fn only_untampered_unexpired_tokens_grant_access() {
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "alice");
+        storage.write(document(1, "shared")).await.unwrap();
+        UserStorage::new(config.clone(), StorageState::default(), "bob")
+            .write(document(1, "private"))
+            .await
+            .unwrap();
+
+        let exp = OffsetDateTime::now_utc().unix_timestamp() + 60;
+        let payload = |sub: &str, exp| SharePayload {
+            sub: sub.to_owned(),
+            id: DocumentIdentifier::from_file_name("1.md").unwrap(),
+            exp,
+        };
+
+        let valid = token(SECRET, &payload("alice", exp));
+        assert_eq!(open(&config, valid.clone()).await.unwrap(), "shared");
+
+        // Another user's payload carrying the original signature
+        let (_, signature) = valid.split_once('.').unwrap();
+        let forged = token(SECRET, &payload("bob", exp));
+        let (forged, _) = forged.split_once('.').unwrap();
+        let tampered = format!("{forged}.{signature}");
+        assert_eq!(open(&config, tampered).await, Err(StatusCode::FORBIDDEN));
+
+        let foreign = token(b"other-secret", &payload("bob", exp));
+        assert_eq!(open(&config, foreign).await, Err(StatusCode::FORBIDDEN));
+
+        let expired = token(SECRET, &payload("alice", exp - 120));
+        assert_eq!(open(&config, expired).await, Err(StatusCode::FORBIDDEN));
+
+        assert_eq!(
+            open(&config, "garbage".into()).await,
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+}
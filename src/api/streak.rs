@@ -0,0 +1,109 @@
+use crate::storage::UserStorage;
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use time::{Date, OffsetDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::warn;
+
+#[derive(Serialize)]
+pub struct Streak {
+    // Consecutive days with at least one entry, ending today or yesterday
+    current_streak: usize,
+    longest_streak: usize,
+    last_entry_date: Option<String>,
+}
+
+pub async fn streak(storage: UserStorage) -> Result<Json<Streak>, StatusCode> {
+    let listing = storage.list().await.map_err(|e| {
+        warn!("Failed to compute streak: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let timezone = storage.config().timezone;
+    let days: BTreeSet<_> = listing
+        .iter()
+        .filter_map(|m| m.identifier.local_datetime(timezone))
+        .map(|datetime| datetime.date())
+        .collect();
+
+    let mut longest_streak = 0;
+    let mut streak = 0;
+    let mut previous: Option<Date> = None;
+
+    for &day in &days {
+        streak = match previous {
+            Some(previous) if previous.next_day() == Some(day) => streak + 1,
+            _ => 1,
+        };
+        longest_streak = longest_streak.max(streak);
+        previous = Some(day);
+    }
+
+    // Today not having an entry yet doesn't break the streak, it just doesn't extend it
+    let today = OffsetDateTime::now_utc().to_timezone(timezone).date();
+    let current_streak = match previous {
+        Some(last) if last == today || last.next_day() == Some(today) => streak,
+        _ => 0,
+    };
+
+    Ok(Json(Streak {
+        current_streak,
+        longest_streak,
+        last_entry_date: previous.map(|day| day.to_string()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        tests::{config, document, storage},
+        StorageConfig,
+    };
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    // Adds entries the given number of days ago and returns the current and longest streak
+    async fn streak_after(config: &StorageConfig, days_ago: &[u64]) -> (usize, usize) {
+        let now = (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64;
+
+        // The offset keeps several entries on the same day apart
+        for (i, days) in days_ago.iter().enumerate() {
+            let identifier = now - days * DAY_MS - i as u64;
+            storage(config.clone())
+                .write(document(identifier, "entry"))
+                .await
+                .unwrap();
+        }
+
+        let Json(streak) = streak(storage(config.clone())).await.unwrap();
+        (streak.current_streak, streak.longest_streak)
+    }
+
+    #[tokio::test]
+    async fn streaks_follow_consecutive_days() {
+        let config = config();
+        assert_eq!(streak_after(&config, &[]).await, (0, 0));
+
+        // A gap two days ago, older days are a longer streak with two entries on one day
+        assert_eq!(
+            streak_after(&config, &[1, 3, 4, 10, 11, 11, 12, 13]).await,
+            (1, 4)
+        );
+
+        // Not having written today yet doesn't break the streak, writing today extends it
+        assert_eq!(streak_after(&config, &[2]).await, (4, 4));
+        assert_eq!(streak_after(&config, &[0]).await, (5, 5));
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streaks_end_after_a_missed_day() {
+        let config = config();
+        assert_eq!(streak_after(&config, &[2, 3, 4]).await, (0, 3));
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    markdown,
+    storage::{DecodedContents, DocumentIdentifier, UserStorage},
+};
+use axum::{http::StatusCode, Extension, Json};
+use futures::{stream, StreamExt, TryStreamExt};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::io;
+use tracing::warn;
+
+// Deleted users are never summarized again, so their entries are only dropped by this bound
+const MAX_CACHED_WORD_COUNTS: usize = 100_000;
+
+// Subject of the user and the document
+type CacheKey = (String, DocumentIdentifier);
+
+/// Word counts of documents, valid as long as the file modification time matches
+#[derive(Clone, Default)]
+pub struct WordCountCache(Arc<RwLock<HashMap<CacheKey, (SystemTime, usize)>>>);
+
+impl WordCountCache {
+    // Drops the user's documents that were deleted or purged since they were counted
+    fn retain_listed(&self, user_id: &str, listed: &HashSet<DocumentIdentifier>) {
+        self.0
+            .write()
+            .retain(|(user, identifier), _| user != user_id || listed.contains(identifier));
+    }
+
+    fn insert(&self, key: CacheKey, value: (SystemTime, usize)) {
+        let mut cache = self.0.write();
+
+        if cache.len() >= MAX_CACHED_WORD_COUNTS {
+            cache.clear();
+        }
+
+        cache.insert(key, value);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    document_count: usize,
+    total_bytes: u64,
+    words: usize,
+
+    // Share of documents whose word count did not have to be recomputed
+    word_count_cache_hit_ratio: f64,
+
+    // Local dates (YYYY-MM-DD) of the oldest and newest document
+    earliest_date: Option<String>,
+    latest_date: Option<String>,
+
+    // Number of documents per local month (YYYY-MM)
+    entries_per_month: BTreeMap<String, usize>,
+}
+
+pub async fn summary(
+    storage: UserStorage,
+    Extension(cache): Extension<WordCountCache>,
+) -> Result<Json<Summary>, StatusCode> {
+    let internal_error = |e| {
+        warn!("Failed to summarize documents: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let listing = storage.list().await.map_err(internal_error)?;
+    let timezone = storage.config().timezone;
+
+    let local_dates: Vec<_> = listing
+        .iter()
+        .filter_map(|m| m.identifier.local_datetime(timezone))
+        .map(|datetime| datetime.date())
+        .collect();
+
+    let mut entries_per_month = BTreeMap::new();
+    for date in &local_dates {
+        let month = format!("{}-{:02}", date.year(), u8::from(date.month()));
+        *entries_per_month.entry(month).or_default() += 1;
+    }
+
+    let documents: Vec<_> = listing.iter().map(|m| (m.identifier, m.modified)).collect();
+    let word_counts: Vec<(usize, bool)> = stream::iter(documents)
+        .map(|(identifier, modified)| count_words(&storage, &cache, identifier, modified))
+        .buffer_unordered(storage.config().read_concurrency)
+        .try_collect()
+        .await
+        .map_err(internal_error)?;
+
+    let cache_hits = word_counts.iter().filter(|(_, hit)| *hit).count();
+
+    let listed = listing.iter().map(|m| m.identifier).collect();
+    cache.retain_listed(storage.user_id(), &listed);
+
+    Ok(Json(Summary {
+        document_count: listing.len(),
+        total_bytes: listing.iter().map(|m| m.size).sum(),
+        words: word_counts.iter().map(|(words, _)| words).sum(),
+        word_count_cache_hit_ratio: if listing.is_empty() {
+            1.0
+        } else {
+            cache_hits as f64 / listing.len() as f64
+        },
+        earliest_date: local_dates.iter().min().map(ToString::to_string),
+        latest_date: local_dates.iter().max().map(ToString::to_string),
+        entries_per_month,
+    }))
+}
+
+// Returns the word count and whether it came from the cache
+async fn count_words(
+    storage: &UserStorage,
+    cache: &WordCountCache,
+    identifier: DocumentIdentifier,
+    modified: Option<SystemTime>,
+) -> io::Result<(usize, bool)> {
+    let key = (storage.user_id().to_owned(), identifier);
+
+    if let Some(modified) = modified {
+        if let Some((cached_modified, words)) = cache.0.read().get(&key) {
+            if *cached_modified == modified {
+                return Ok((*words, true));
+            }
+        }
+    }
+
+    let words = match storage.read_decoded(identifier).await? {
+        // Counted like the word count of a single document, without the front matter
+        DecodedContents::Text { contents, .. } => {
+            markdown::word_count(markdown::split_front_matter(&contents).1)
+        }
+        DecodedContents::Binary(_) => 0,
+    };
+
+    if let Some(modified) = modified {
+        cache.insert(key, (modified, words));
+    }
+
+    Ok((words, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{entries, tests::body, Representation, X_TOTAL_COUNT},
+        storage::tests::{config, document, storage},
+    };
+    use axum::{extract::Query, http::Uri};
+
+    async fn summarize(cache: &WordCountCache, storage: UserStorage) -> Summary {
+        summary(storage, Extension(cache.clone())).await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn unchanged_documents_are_not_read_again() {
+        let config = config();
+        let cache = WordCountCache::default();
+        storage(config.clone())
+            .create("---\ntitle: Front matter\n---\nTwo words".into())
+            .await
+            .unwrap();
+        storage(config.clone())
+            .create("Three more words -".into())
+            .await
+            .unwrap();
+
+        let first = summarize(&cache, storage(config.clone())).await;
+        assert_eq!(first.words, 5);
+        assert_eq!(first.word_count_cache_hit_ratio, 0.0);
+
+        let second = summarize(&cache, storage(config)).await;
+        assert_eq!(second.words, 5);
+        assert_eq!(second.word_count_cache_hit_ratio, 1.0);
+    }
+
+    #[tokio::test]
+    async fn deleted_documents_are_evicted() {
+        let config = config();
+        let cache = WordCountCache::default();
+        let identifier = storage(config.clone())
+            .create("Soon gone".into())
+            .await
+            .unwrap();
+
+        summarize(&cache, storage(config.clone())).await;
+        assert_eq!(cache.0.read().len(), 1);
+
+        let path = storage(config.clone()).doc_path(identifier).unwrap();
+        tokio::fs::remove_file(path).await.unwrap();
+
+        summarize(&cache, storage(config)).await;
+        assert!(cache.0.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn summary_matches_the_listing() {
+        let config = config();
+        let seeded = storage(config.clone());
+        // 2024-01-15, 2024-01-20 and 2024-03-02 at noon UTC
+        for (identifier, contents) in [
+            (1_705_320_000_000, "---\ntitle: First\n---\nOne two three"),
+            (1_705_752_000_000, "Four *five*"),
+            (1_709_380_800_000, "# Six\n\nseven eight nine ten"),
+        ] {
+            seeded.write(document(identifier, contents)).await.unwrap();
+        }
+
+        let summary = summarize(&WordCountCache::default(), storage(config.clone())).await;
+
+        let uri: Uri = "/api/document".parse().unwrap();
+        let response = entries(
+            Query::try_from_uri(&uri).unwrap(),
+            Representation::Plain,
+            storage(config.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.headers()[X_TOTAL_COUNT], "3");
+        let listing: Vec<serde_json::Value> =
+            serde_json::from_slice(&body(response).await).unwrap();
+
+        let dates: Vec<_> = listing
+            .iter()
+            .map(|d| d["date"].as_str().unwrap())
+            .collect();
+        let words: u64 = listing
+            .iter()
+            .map(|d| d["word_count"].as_u64().unwrap())
+            .sum();
+        let bytes: u64 = seeded.list().await.unwrap().iter().map(|m| m.size).sum();
+
+        assert_eq!(summary.document_count, listing.len());
+        assert_eq!(summary.words as u64, words);
+        assert_eq!(summary.total_bytes, bytes);
+        assert_eq!(
+            summary.earliest_date.as_deref(),
+            dates.iter().min().copied()
+        );
+        assert_eq!(summary.latest_date.as_deref(), dates.iter().max().copied());
+        assert_eq!(
+            summary.entries_per_month,
+            BTreeMap::from([("2024-01".to_owned(), 2), ("2024-03".to_owned(), 1)])
+        );
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+}
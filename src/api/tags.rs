@@ -0,0 +1,91 @@
+use crate::storage::{Document, UserStorage, TRUNCATE_LEN};
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Json,
+};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use tokio::io::ErrorKind;
+use tracing::warn;
+
+const DEFAULT_TAG_LIMIT: usize = 5;
+
+#[derive(Deserialize)]
+pub struct TagQuery {
+    limit: Option<usize>,
+}
+
+/// Most recent documents carrying a tag in their front matter, newest first with previews.
+/// Reading stops as soon as enough documents have been found.
+pub async fn tagged(
+    Path(tag): Path<String>,
+    Query(query): Query<TagQuery>,
+    storage: UserStorage,
+) -> Result<Json<Vec<Document>>, StatusCode> {
+    let internal_error = |e| {
+        warn!("Failed to collect tagged documents: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TAG_LIMIT)
+        .min(storage.config().max_listing);
+
+    let listing = storage.list().await.map_err(internal_error)?;
+
+    let documents = stream::iter(listing)
+        .map(|metadata| storage.read(metadata.identifier, Some(TRUNCATE_LEN)))
+        .buffered(storage.config().read_concurrency)
+        .filter_map(|result| {
+            future::ready(match result {
+                // Deleted since it was listed
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                result => Some(result),
+            })
+        })
+        .try_filter(|document| future::ready(document.tags.contains(&tag)))
+        .take(limit)
+        .try_collect()
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(documents))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::tests::TestApi, storage::tests::config};
+
+    #[tokio::test]
+    async fn only_tagged_entries_are_returned_newest_first() {
+        let api = TestApi::new(config()).await;
+        api.seed(&[
+            (1, "---\ntags: [work]\n---\nOldest\n"),
+            (2, "Untagged\n"),
+            (3, "---\ntags: [idea, work]\n---\nMiddle\n"),
+            (4, "---\ntags: [idea]\n---\nOther tag\n"),
+            (5, "---\ntags: [work]\n---\nNewest\n"),
+        ])
+        .await;
+
+        let contents = |documents: serde_json::Value| -> Vec<String> {
+            documents
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|document| document["contents"].as_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let tagged = api.json("/api/tags/work").await;
+        assert_eq!(contents(tagged), ["Newest\n", "Middle\n", "Oldest\n"]);
+
+        let tagged = api.json("/api/tags/work?limit=2").await;
+        assert_eq!(contents(tagged), ["Newest\n", "Middle\n"]);
+
+        let tagged = api.json("/api/tags/unused").await;
+        assert_eq!(contents(tagged), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::storage::{DecodedContents, DocumentIdentifier, UserStorage};
+use axum::{extract::Query, http::StatusCode, Json};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tracing::warn;
+
+#[derive(Deserialize)]
+pub struct TaskQuery {
+    done: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Task {
+    identifier: DocumentIdentifier,
+    // 1-based line number within the document
+    line: usize,
+    text: String,
+    done: bool,
+}
+
+pub async fn tasks(
+    Query(query): Query<TaskQuery>,
+    storage: UserStorage,
+) -> Result<Json<Vec<Task>>, StatusCode> {
+    super::require_feature(&storage, super::FEATURE_TASKS).await?;
+
+    let internal_error = |e| {
+        warn!("Failed to collect tasks: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let listing = storage.list().await.map_err(internal_error)?;
+    let identifiers: Vec<_> = listing.iter().map(|m| m.identifier).collect();
+
+    // Buffered in order so tasks stay sorted like the listing, newest document first
+    let tasks: Vec<Vec<Task>> = stream::iter(identifiers)
+        .map(|identifier| document_tasks(&storage, identifier))
+        .buffered(storage.config().read_concurrency)
+        .try_collect()
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        tasks
+            .into_iter()
+            .flatten()
+            .filter(|task| query.done.is_none_or(|done| task.done == done))
+            .collect(),
+    ))
+}
+
+async fn document_tasks(
+    storage: &UserStorage,
+    identifier: DocumentIdentifier,
+) -> io::Result<Vec<Task>> {
+    let DecodedContents::Text { contents, .. } = storage.read_decoded(identifier).await? else {
+        return Ok(Vec::new());
+    };
+
+    let mut in_fence = false;
+    let mut tasks = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+
+        if ["```", "~~~"]
+            .iter()
+            .any(|fence| trimmed.starts_with(fence))
+        {
+            in_fence = !in_fence;
+            continue;
+        }
+
+        if in_fence {
+            continue;
+        }
+
+        if let Some((done, text)) = parse_task(trimmed) {
+            tasks.push(Task {
+                identifier,
+                line: index + 1,
+                text: text.to_owned(),
+                done,
+            });
+        }
+    }
+
+    Ok(tasks)
+}
+
+// Matches list items like `- [ ] text`, `* [x] text` or `1. [X] text`
+fn parse_task(line: &str) -> Option<(bool, &str)> {
+    let item = match line.strip_prefix(['-', '*', '+']) {
+        Some(item) => item,
+        None => {
+            let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            line[digits..]
+                .strip_prefix(['.', ')'])
+                .filter(|_| digits > 0)?
+        }
+    };
+
+    let item = item.strip_prefix([' ', '\t'])?.trim_start();
+    let done = match item.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+
+    let text = &item[3..];
+    if !text.is_empty() && !text.starts_with([' ', '\t']) {
+        return None;
+    }
+
+    Some((done, text.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::FEATURE_TASKS,
+        storage::{
+            tests::{config, document, storage},
+            StorageConfig, StorageState,
+        },
+    };
+    use axum::http::Uri;
+
+    const CHECKLIST: &str = "\
+# Today
+
+- [ ] Water plants
+* [x] Call mom
+1. [X] Pay rent
+- [] Not a task
+- [x]Neither
+```
+- [ ] Inside a code block
+```
+  + [ ] Nested errand
+";
+
+    async fn tasks_for(storage: UserStorage, query: &str) -> Vec<serde_json::Value> {
+        let uri: Uri = format!("/api/tasks{query}").parse().unwrap();
+        let Json(tasks) = tasks(Query::try_from_uri(&uri).unwrap(), storage)
+            .await
+            .unwrap();
+
+        tasks
+            .into_iter()
+            .map(|task| serde_json::to_value(task).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tasks_are_collected_with_their_state() {
+        let config = config();
+        storage(config.clone())
+            .write(document(1, CHECKLIST))
+            .await
+            .unwrap();
+        storage(config.clone())
+            .write(document(2, "- [ ] Newer task"))
+            .await
+            .unwrap();
+
+        let task = |identifier: u64, line: usize, text: &str, done: bool| serde_json::json!({ "identifier": identifier, "line": line, "text": text, "done": done });
+
+        assert_eq!(
+            tasks_for(storage(config.clone()), "").await,
+            [
+                task(2, 1, "Newer task", false),
+                task(1, 3, "Water plants", false),
+                task(1, 4, "Call mom", true),
+                task(1, 5, "Pay rent", true),
+                task(1, 11, "Nested errand", false),
+            ]
+        );
+
+        assert_eq!(
+            tasks_for(storage(config.clone()), "?done=true").await,
+            [task(1, 4, "Call mom", true), task(1, 5, "Pay rent", true)]
+        );
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gated_tasks_are_only_available_with_the_flag() {
+        let config = StorageConfig {
+            gated_features: vec![FEATURE_TASKS.to_owned()],
+            ..config()
+        };
+        let uri: Uri = "/api/tasks".parse().unwrap();
+        let status = |storage| {
+            let query = Query::try_from_uri(&uri).unwrap();
+            async move {
+                match tasks(query, storage).await {
+                    Ok(_) => StatusCode::OK,
+                    Err(status) => status,
+                }
+            }
+        };
+
+        let alice = storage(config.clone());
+        alice
+            .set_features(&[FEATURE_TASKS.to_owned()].into())
+            .await
+            .unwrap();
+        assert_eq!(status(alice).await, StatusCode::OK);
+
+        let bob = UserStorage::new(config.clone(), StorageState::default(), "bob");
+        assert_eq!(status(bob).await, StatusCode::NOT_FOUND);
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+}
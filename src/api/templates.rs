@@ -0,0 +1,154 @@
+use crate::{auth::AuthenticatedUser, storage::StorageConfig};
+use axum::{extract::Path, http::StatusCode, Extension, Json};
+use time::OffsetDateTime;
+use time_tz::OffsetDateTimeExt;
+use tokio::{fs, io};
+use tracing::warn;
+
+const TEMPLATE_EXTENSION: &str = "md";
+
+pub async fn list(
+    _: AuthenticatedUser,
+    Extension(config): Extension<StorageConfig>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    let Some(dir) = &config.templates_dir else {
+        return Ok(Json(Vec::new()));
+    };
+
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(dir).await.map_err(|e| {
+        warn!("Failed to list templates: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+
+        if path.extension().is_some_and(|e| e == TEMPLATE_EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                if is_valid_name(name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+    }
+
+    names.sort();
+    Ok(Json(names))
+}
+
+pub async fn get(
+    _: AuthenticatedUser,
+    Path(name): Path<String>,
+    Extension(config): Extension<StorageConfig>,
+) -> Result<String, StatusCode> {
+    render(&config, &name).await
+}
+
+/// Loads a template by name and fills in the placeholders for the current local time
+pub async fn render(config: &StorageConfig, name: &str) -> Result<String, StatusCode> {
+    let Some(dir) = &config.templates_dir else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // Names come straight from the request, so they must not be able to leave the directory
+    if !is_valid_name(name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let path = dir.join(name).with_extension(TEMPLATE_EXTENSION);
+    let template = fs::read_to_string(path).await.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => {
+            warn!("Failed to read template {name}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    })?;
+
+    let now = OffsetDateTime::now_utc().to_timezone(config.timezone);
+
+    Ok(template
+        .replace("{{date}}", &now.date().to_string())
+        .replace(
+            "{{time}}",
+            &format!("{:02}:{:02}", now.hour(), now.minute()),
+        )
+        .replace("{{weekday}}", &now.weekday().to_string()))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::tests::{body, TestApi},
+        storage::tests::config,
+    };
+    use axum::http::header::LOCATION;
+
+    async fn api_with_templates() -> TestApi {
+        let config = config();
+        let dir = config.location.join("templates");
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("morning.md"), "# Morning of {{date}}\n\n")
+            .await
+            .unwrap();
+        fs::write(dir.join("meeting-notes.md"), "Attendees:\n")
+            .await
+            .unwrap();
+        fs::write(dir.join("readme.txt"), "not a template")
+            .await
+            .unwrap();
+
+        TestApi::new(StorageConfig {
+            templates_dir: Some(dir),
+            ..config
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn templates_are_listed_and_rendered() {
+        let api = api_with_templates().await;
+
+        let names = api.json("/api/templates").await;
+        assert_eq!(names, serde_json::json!(["meeting-notes", "morning"]));
+
+        let today = OffsetDateTime::now_utc().date();
+        let response = api.get("/api/templates/morning").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            format!("# Morning of {today}\n\n").as_bytes()
+        );
+
+        for unknown in ["evening", "readme", "..%2Fmorning"] {
+            let response = api.get(&format!("/api/templates/{unknown}")).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{unknown}");
+        }
+    }
+
+    #[tokio::test]
+    async fn documents_are_created_from_templates() {
+        let api = api_with_templates().await;
+
+        let response = api
+            .post("/api/document?template=meeting-notes", "Alice, Bob\n")
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()[LOCATION].to_str().unwrap().to_owned();
+        assert_eq!(
+            body(api.get(&location).await).await,
+            b"Attendees:\nAlice, Bob\n"
+        );
+
+        let response = api.post("/api/document?template=evening", "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,158 @@
+use super::USER_COOKIE;
+use crate::storage::UserStorage;
+use axum::{
+    body::Bytes,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+        StatusCode,
+    },
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::CookieJar;
+use openidconnect::{core::CoreGenderClaim, StandardClaims};
+use tokio::io::ErrorKind;
+use tracing::warn;
+use url::Url;
+
+const MAX_AVATAR_SIZE: usize = 512 * 1024;
+
+// Only raster formats are accepted, SVGs could carry scripts
+const IMAGE_SIGNATURES: [(&[u8], &str); 4] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF8", "image/gif"),
+    (b"RIFF", "image/webp"),
+];
+
+fn content_type(image: &[u8]) -> Option<&'static str> {
+    IMAGE_SIGNATURES
+        .iter()
+        .find(|(signature, _)| image.starts_with(signature))
+        .filter(|(_, content_type)| {
+            *content_type != "image/webp" || image.get(8..12) == Some(b"WEBP")
+        })
+        .map(|(_, content_type)| *content_type)
+}
+
+pub async fn avatar(storage: UserStorage, jar: CookieJar) -> Result<Response, StatusCode> {
+    match storage.read_avatar().await {
+        Ok(image) => {
+            let content_type = content_type(&image).ok_or_else(|| {
+                warn!("Stored avatar is not a supported image");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+            Ok((
+                [
+                    (CONTENT_TYPE, content_type),
+                    (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+                    (CACHE_CONTROL, "private, no-cache"),
+                ],
+                image,
+            )
+                .into_response())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => claimed_picture(&jar)
+            .map(|url| Redirect::temporary(&url).into_response())
+            .ok_or(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Failed to read avatar: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn upload(storage: UserStorage, image: Bytes) -> StatusCode {
+    if image.len() > MAX_AVATAR_SIZE {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+
+    if content_type(&image).is_none() {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    }
+
+    match storage.write_avatar(&image).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            warn!("Failed to write avatar: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+// The user cookie holds the claims from the last login, including the IdP's picture
+fn claimed_picture(jar: &CookieJar) -> Option<String> {
+    let claims: StandardClaims<CoreGenderClaim> =
+        serde_json::from_str(jar.get(USER_COOKIE)?.value()).ok()?;
+    let url = Url::parse(claims.picture()?.get(None)?).ok()?;
+
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::tests::body,
+        storage::tests::{config, storage},
+    };
+    use axum::http::header::LOCATION;
+    use axum_extra::extract::cookie::Cookie;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nrest of the image";
+
+    fn jar_with_picture(picture: &str) -> CookieJar {
+        let claims = serde_json::json!({ "sub": "alice", "picture": picture });
+        CookieJar::new().add(Cookie::new(USER_COOKIE, claims.to_string()))
+    }
+
+    #[tokio::test]
+    async fn uploaded_avatars_are_served() {
+        let config = config();
+
+        assert_eq!(
+            upload(storage(config.clone()), Bytes::from_static(b"<svg/>")).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            upload(
+                storage(config.clone()),
+                Bytes::from(vec![0xff; MAX_AVATAR_SIZE + 1])
+            )
+            .await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            upload(storage(config.clone()), Bytes::from_static(PNG)).await,
+            StatusCode::NO_CONTENT
+        );
+
+        // The upload takes precedence over the claimed picture
+        let jar = jar_with_picture("https://idp.example.com/alice.png");
+        let response = avatar(storage(config.clone()), jar).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(body(response).await, PNG);
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn claimed_picture_is_the_fallback() {
+        let config = config();
+
+        let jar = jar_with_picture("https://idp.example.com/alice.png");
+        let response = avatar(storage(config.clone()), jar).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://idp.example.com/alice.png"
+        );
+
+        let jar = jar_with_picture("javascript:alert(1)");
+        let response = avatar(storage(config.clone()), jar).await;
+        assert_eq!(response.unwrap_err(), StatusCode::NOT_FOUND);
+
+        let response = avatar(storage(config), CookieJar::new()).await;
+        assert_eq!(response.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+}
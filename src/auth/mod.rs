@@ -1,27 +1,42 @@
 use axum::{
     async_trait,
     body::Body,
-    extract::{FromRequestParts, Query},
-    http::{header::REFERER, request::Parts, HeaderMap, StatusCode},
+    extract::{ConnectInfo, FromRequestParts, Query},
+    http::{
+        header::{REFERER, RETRY_AFTER, SET_COOKIE},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode,
+    },
+    middleware::{self, Next},
     response::{Html, IntoResponse, Redirect, Response},
     routing::get,
-    Extension, Router,
+    Extension, Json, Router,
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
-use openidconnect::{AccessToken, AuthorizationCode, CsrfToken};
+use openidconnect::{
+    core::CoreRevocableToken, AccessToken, AuthorizationCode, CsrfToken, RefreshToken,
+};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::convert::Infallible;
-use time::Duration;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use url::{Position, Url};
 
+mod avatar;
 pub mod oauth;
 pub mod oidc;
 pub use oidc::AuthenticatedUser;
 
-// This can be changed for local development but really should be true in production
-const REQUIRE_HTTPS: bool = false;
-const AUTH_COOKIE: &'static str = "auth";
-const USER_COOKIE: &'static str = "user";
-const REDIRECT_COOKIE: &'static str = "redirectURL";
+const AUTH_COOKIE: &str = "auth";
+const USER_COOKIE: &str = "user";
+const REFRESH_COOKIE: &str = "refresh";
+const REDIRECT_COOKIE: &str = "redirectURL";
+pub const X_TOKEN_EXPIRES_AT: HeaderName = HeaderName::from_static("x-token-expires-at");
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+const POPUP_COOKIE: &str = "loginPopup";
+const MAX_REFERRER_LEN: usize = 2048;
 
 #[derive(Deserialize)]
 pub struct CallbackData {
@@ -29,67 +44,187 @@ pub struct CallbackData {
     state: CsrfToken,
 }
 
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginMode {
+    #[default]
+    Redirect,
+    // The login runs in a popup which reports back to its opener instead of navigating
+    Popup,
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    mode: LoginMode,
+
+    // Overrides the configured prompt, e.g. `login` to force re-authentication
+    prompt: Option<oidc::Prompt>,
+}
+
 pub fn router() -> Router<(), Body> {
     Router::<(), Body>::new()
-        .route("/login", get(login))
-        .route("/callback", get(callback))
+        .route(
+            "/login",
+            get(login).layer(middleware::from_fn(limit_login_rate)),
+        )
+        .route(
+            "/callback",
+            get(callback).layer(middleware::from_fn(limit_login_rate)),
+        )
         .route("/success", get(success))
         .route("/failed", get(failed))
+        .route("/logout", get(logout))
+        .route("/avatar", get(avatar::avatar).put(avatar::upload))
+        .route("/me", get(me))
+}
+
+// Every login allocates a pending session, so clients can't be allowed to start them endlessly
+async fn limit_login_rate<B>(request: Request<B>, next: Next<B>) -> Response {
+    let auth_client = request
+        .extensions()
+        .get::<oidc::AuthClient>()
+        .expect("missing AuthClient extension");
+
+    let forwarded_for = auth_client
+        .trust_forwarded_for()
+        .then(|| request.headers().get(X_FORWARDED_FOR))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|client| client.trim().parse().ok());
+
+    let client = forwarded_for.or_else(|| {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip())
+    });
+
+    if let Some(client) = client {
+        if let Err(retry_after) = auth_client.limit_login_rate(client) {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+                "Too many login attempts, try again later.",
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
 }
 
 async fn login(
+    Query(query): Query<LoginQuery>,
     mut jar: CookieJar,
     // TODO Use an extension instead!
     Extension(auth_client): Extension<oidc::AuthClient>,
     headers: HeaderMap,
 ) -> (CookieJar, Redirect) {
-    let (auth_session, auth_url) = auth_client.create_session();
-
-    if let Some(referrer) = headers.get(REFERER).map(|h| h.to_str().ok()).flatten() {
-        jar = jar.add(
-            Cookie::build(REDIRECT_COOKIE, referrer.to_owned())
-                .secure(REQUIRE_HTTPS)
-                .max_age(Duration::MINUTE * 5)
-                .same_site(SameSite::Lax)
-                .http_only(true)
-                .path("/")
-                .finish(),
-        );
+    let (auth_session, auth_url) = auth_client.create_session(query.prompt);
+    let secure = auth_client.require_https();
+
+    let referrer = headers
+        .get(REFERER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|referrer| local_path(referrer, auth_client.redirect_url()));
+
+    if query.mode == LoginMode::Popup {
+        jar = jar.add(login_cookie(POPUP_COOKIE, "true".into(), secure));
+    } else {
+        // Left over from an abandoned popup login, it would turn this one into a popup too
+        jar = jar.remove(removal_cookie(POPUP_COOKIE));
+
+        if let Some(referrer) = referrer {
+            jar = jar.add(login_cookie(REDIRECT_COOKIE, referrer, secure));
+        }
     }
 
     (
-        AuthState::Pending(auth_session).write_to_jar(jar),
+        AuthState::Pending(auth_session).write_to_jar(jar, secure),
         Redirect::to(auth_url.as_str()),
     )
 }
 
+// Short-lived cookie carrying state from the login to the success or failure page
+fn login_cookie(name: &'static str, value: String, secure: bool) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .secure(secure)
+        .max_age(Duration::MINUTE * 5)
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .path("/")
+        .finish()
+}
+
+// Removal cookies need the same path as the original, otherwise browsers keep the cookie
+fn removal_cookie(name: &'static str) -> Cookie<'static> {
+    Cookie::build(name, "").path("/").finish()
+}
+
+// Only referrers from our own origin are followed after login, and only by their path
+fn local_path(referrer: &str, origin: &Url) -> Option<String> {
+    if referrer.len() > MAX_REFERRER_LEN {
+        return None;
+    }
+
+    let referrer = Url::parse(referrer).ok()?;
+
+    if referrer.origin() != origin.origin() {
+        return None;
+    }
+
+    Some(referrer[Position::BeforePath..].to_owned())
+}
+
 async fn callback(
     Query(data): Query<CallbackData>,
     jar: CookieJar,
     Extension(auth_client): Extension<oidc::AuthClient>,
 ) -> (CookieJar, Redirect) {
-    if let AuthState::Pending(session) = AuthState::from_jar(&jar) {
-        if let Some(auth) = auth_client
-            .authenticate(session, data.code, data.state)
-            .await
-        {
-            let user_cookie = build_user_cookie(&auth);
-            return (
-                AuthState::Authenticated(auth.access_token)
-                    .write_to_jar(jar)
-                    .add(user_cookie),
-                Redirect::to("./success"),
-            );
+    match AuthState::from_jar(&jar) {
+        // Repeated callbacks share the outcome of the first one and set the same cookies again
+        AuthState::Pending(session) if auth_client.knows_session(&session) => {
+            if let Some(auth) = auth_client
+                .authenticate(session, data.code, data.state)
+                .await
+            {
+                let secure = auth_client.require_https();
+                let mut jar = jar.add(build_user_cookie(&auth, secure));
+
+                if let Some(refresh_token) = auth.refresh_token {
+                    jar = jar.add(build_refresh_cookie(refresh_token, secure));
+                }
+
+                return (
+                    AuthState::Authenticated(auth.access_token).write_to_jar(jar, secure),
+                    Redirect::to("./success"),
+                );
+            }
         }
-    };
+        // Expired, or repeated long after it completed, so the login has to be started over
+        AuthState::Pending(_) => return (jar, Redirect::to("./login")),
+        // Repeated after the cookies of the first callback already arrived
+        AuthState::Authenticated(token) if auth_client.introspect(&token).await.is_some() => {
+            return (jar, Redirect::to("./success"));
+        }
+        _ => {}
+    }
 
     (jar, Redirect::to("./failed"))
 }
 
 async fn success(jar: CookieJar) -> Response {
-    if let Some(destination) = jar.get(REDIRECT_COOKIE).cloned() {
+    if jar.get(POPUP_COOKIE).is_some() {
+        (
+            jar.remove(removal_cookie(POPUP_COOKIE)),
+            popup_result("success", "Login successful."),
+        )
+            .into_response()
+    } else if let Some(destination) = jar.get(REDIRECT_COOKIE).cloned() {
         (
-            jar.remove(Cookie::named(REDIRECT_COOKIE)),
+            jar.remove(removal_cookie(REDIRECT_COOKIE)),
             Html(format!(
                 r#"
                     Login successful.
@@ -104,25 +239,193 @@ async fn success(jar: CookieJar) -> Response {
     }
 }
 
-async fn failed() -> (StatusCode, &'static str) {
-    (
-        StatusCode::UNAUTHORIZED,
-        "Login failed. See server logs for more details.",
+async fn failed(jar: CookieJar) -> Response {
+    if jar.get(POPUP_COOKIE).is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            jar.remove(removal_cookie(POPUP_COOKIE)),
+            popup_result("failed", "Login failed. See server logs for more details."),
+        )
+            .into_response()
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            "Login failed. See server logs for more details.",
+        )
+            .into_response()
+    }
+}
+
+async fn logout(
+    jar: CookieJar,
+    Extension(auth_client): Extension<oidc::AuthClient>,
+) -> (CookieJar, Redirect) {
+    if let AuthState::Authenticated(token) = AuthState::from_jar(&jar) {
+        // Has to happen first, the subject can't be looked up once the token is revoked
+        auth_client.forget(&token).await;
+        auth_client.revoke(CoreRevocableToken::from(&token)).await;
+    }
+
+    // Otherwise the session could be revived from the refresh token
+    if let Some(cookie) = jar.get(REFRESH_COOKIE) {
+        let refresh_token = RefreshToken::new(cookie.value().to_owned());
+        auth_client
+            .revoke(CoreRevocableToken::from(&refresh_token))
+            .await;
+    }
+
+    let jar = jar
+        .remove(removal_cookie(AUTH_COOKIE))
+        .remove(removal_cookie(USER_COOKIE))
+        .remove(removal_cookie(REFRESH_COOKIE));
+
+    // Without an end-session endpoint the IdP session outlives ours, but that's all we can do
+    let destination = auth_client
+        .logout_url()
+        .map(String::from)
+        .unwrap_or_else(|| "/".into());
+
+    (jar, Redirect::to(&destination))
+}
+
+// Only same-origin openers receive the message, which is all the frontend needs
+fn popup_result(result: &'static str, message: &'static str) -> Html<String> {
+    Html(format!(
+        r#"
+            {message}
+            <script>
+                if (window.opener) {{
+                    window.opener.postMessage({{ login: "{result}" }}, window.location.origin);
+                }}
+                window.close();
+            </script>
+        "#
+    ))
+}
+
+#[derive(Serialize)]
+struct Me {
+    subject: String,
+    username: String,
+    // RFC 3339, clients can re-authenticate silently before this point
+    expires_at: String,
+}
+
+async fn me(user: AuthenticatedUser) -> Result<Response, StatusCode> {
+    let expires_at = OffsetDateTime::from_unix_timestamp(user.expiry)
+        .ok()
+        .and_then(|expiry| expiry.format(&Rfc3339).ok())
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [(X_TOKEN_EXPIRES_AT, expires_at.clone())],
+        Json(Me {
+            subject: user.subject,
+            username: user.username,
+            expires_at,
+        }),
     )
+        .into_response())
 }
 
-fn build_user_cookie(data: &oidc::AuthData) -> Cookie<'static> {
+fn build_user_cookie(data: &oidc::AuthData, secure: bool) -> Cookie<'static> {
     Cookie::build(
         USER_COOKIE,
         serde_json::to_string(&data.user).expect("failed to serialize user cookie"),
     )
-    .secure(REQUIRE_HTTPS)
+    .secure(secure)
     .max_age(Duration::DAY)
     .same_site(SameSite::Strict)
     .path("/")
     .finish()
 }
 
+// Outlives the access token by far, it is what keeps users logged in between visits
+fn build_refresh_cookie(refresh_token: RefreshToken, secure: bool) -> Cookie<'static> {
+    Cookie::build(REFRESH_COOKIE, refresh_token.secret().clone())
+        .secure(secure)
+        .http_only(true)
+        .max_age(Duration::DAY * 30)
+        .same_site(SameSite::Strict)
+        .path("/")
+        .finish()
+}
+
+/// Outcome of a refresh while extracting the request's authentication, which only the
+/// `persist_refreshed_tokens` middleware can write to the response cookies
+#[derive(Clone, Default)]
+struct RefreshedTokens(Arc<Mutex<Option<RefreshOutcome>>>);
+
+enum RefreshOutcome {
+    Refreshed(oidc::TokenPair),
+    // The refresh cookie is dropped so it doesn't trigger another exchange with every request
+    Failed,
+}
+
+pub async fn persist_refreshed_tokens<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let refreshed = RefreshedTokens::default();
+    request.extensions_mut().insert(refreshed.clone());
+
+    let secure = request
+        .extensions()
+        .get::<oidc::AuthClient>()
+        .is_none_or(oidc::AuthClient::require_https);
+
+    let mut response = next.run(request).await;
+
+    let cookies = match refreshed.0.lock().take() {
+        Some(RefreshOutcome::Refreshed((access_token, refresh_token))) => {
+            std::iter::once(AuthState::Authenticated(access_token).cookie(secure))
+                .chain(refresh_token.map(|token| build_refresh_cookie(token, secure)))
+                .collect()
+        }
+        Some(RefreshOutcome::Failed) => {
+            let mut removal = removal_cookie(REFRESH_COOKIE);
+            removal.make_removal();
+            vec![removal]
+        }
+        None => Vec::new(),
+    };
+
+    for cookie in cookies {
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    response
+}
+
+// Stands in for a missing or expired access token as long as the refresh token is still valid
+async fn refresh(
+    parts: &Parts,
+    auth_client: &oidc::AuthClient,
+    jar: &CookieJar,
+) -> Option<(AccessToken, AuthenticatedUser)> {
+    let refresh_token = RefreshToken::new(jar.get(REFRESH_COOKIE)?.value().to_owned());
+
+    let refreshed = match auth_client.refresh(&refresh_token).await {
+        Some((access_token, rotated)) => auth_client
+            .introspect(&access_token)
+            .await
+            .map(|user| (access_token, rotated, user)),
+        None => None,
+    };
+
+    let outcome = match &refreshed {
+        Some((access_token, rotated, _)) => {
+            RefreshOutcome::Refreshed((access_token.clone(), rotated.clone()))
+        }
+        None => RefreshOutcome::Failed,
+    };
+
+    if let Some(slot) = parts.extensions.get::<RefreshedTokens>() {
+        *slot.0.lock() = Some(outcome);
+    }
+
+    refreshed.map(|(access_token, _, user)| (access_token, user))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AuthState {
     Pending(oidc::AuthSession),
@@ -135,12 +438,15 @@ pub enum AuthState {
 impl AuthState {
     fn from_jar(jar: &CookieJar) -> AuthState {
         jar.get(AUTH_COOKIE)
-            .map(|cookie| serde_json::from_str(cookie.value()).ok())
-            .flatten()
+            .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
             .unwrap_or(AuthState::Unauthenticated)
     }
 
-    fn write_to_jar(&self, jar: CookieJar) -> CookieJar {
+    fn write_to_jar(&self, jar: CookieJar, secure: bool) -> CookieJar {
+        jar.add(self.cookie(secure))
+    }
+
+    fn cookie(&self, secure: bool) -> Cookie<'static> {
         // For the callback to work the pending cookie has to be set as lax
         let same_site = match &self {
             AuthState::Pending(_) => SameSite::Lax,
@@ -148,15 +454,13 @@ impl AuthState {
         };
 
         let value = serde_json::to_string(&self).expect("failed to serialize AuthState");
-        let cookie = Cookie::build(AUTH_COOKIE, value)
-            .secure(REQUIRE_HTTPS)
+        Cookie::build(AUTH_COOKIE, value)
+            .secure(secure)
             .http_only(true)
             .max_age(self.validity_period())
             .same_site(same_site)
             .path("/")
-            .finish();
-
-        jar.add(cookie)
+            .finish()
     }
 
     fn validity_period(&self) -> Duration {
@@ -182,17 +486,17 @@ where
             .expect("missing AuthClient extension");
 
         let jar = CookieJar::from_headers(&parts.headers);
-        let state = Self::from_jar(&jar);
-
-        Ok(if let AuthState::Authenticated(token) = &state {
-            // Make sure the token is still valid!
-            if auth_client.introspect(token).await.is_none() {
-                AuthState::Unauthenticated
-            } else {
-                state
+
+        // Make sure the token is still valid!
+        Ok(match Self::from_jar(&jar) {
+            AuthState::Pending(session) => AuthState::Pending(session),
+            AuthState::Authenticated(token) if auth_client.introspect(&token).await.is_some() => {
+                AuthState::Authenticated(token)
             }
-        } else {
-            state
+            _ => match refresh(parts, auth_client, &jar).await {
+                Some((token, _)) => AuthState::Authenticated(token),
+                None => AuthState::Unauthenticated,
+            },
         })
     }
 }
@@ -204,25 +508,306 @@ where
 {
     type Rejection = (StatusCode, Html<&'static str>);
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         const UNAUTHORIZED: (StatusCode, Html<&'static str>) = (
             StatusCode::UNAUTHORIZED,
             Html("Unauthorized. <a href=\"/auth/login\">Login -></a>"),
         );
 
-        if let AuthState::Authenticated(token) = AuthState::from_request_parts(parts, state)
+        let auth_client = parts
+            .extensions
+            .get::<oidc::AuthClient>()
+            .expect("missing AuthClient extension");
+
+        // Reading the cookie directly, going through AuthState would introspect the token twice
+        let jar = CookieJar::from_headers(&parts.headers);
+
+        if let AuthState::Authenticated(token) = AuthState::from_jar(&jar) {
+            if let Some(user) = auth_client.introspect(&token).await {
+                return Ok(user);
+            }
+        }
+
+        refresh(parts, auth_client, &jar)
             .await
-            .map_err(|_| UNAUTHORIZED)?
-        {
-            parts
-                .extensions
-                .get::<oidc::AuthClient>()
-                .expect("missing AuthClient extension")
-                .introspect(&token)
-                .await
-                .ok_or(UNAUTHORIZED)
-        } else {
-            Err(UNAUTHORIZED)
+            .map(|(_, user)| user)
+            .ok_or(UNAUTHORIZED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::body;
+    use axum::{http::header::LOCATION, Extension};
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    async fn request_me(auth_client: &oidc::AuthClient, cookie: &str) -> Response {
+        request(auth_client, "/me", cookie).await
+    }
+
+    async fn request(auth_client: &oidc::AuthClient, uri: &str, cookie: &str) -> Response {
+        let request = Request::builder()
+            .uri(uri)
+            .header("cookie", cookie)
+            .body(Body::empty())
+            .unwrap();
+
+        send(auth_client, request).await
+    }
+
+    async fn send(auth_client: &oidc::AuthClient, request: Request<Body>) -> Response {
+        let app = router()
+            .layer(middleware::from_fn(persist_refreshed_tokens))
+            .layer(Extension(auth_client.clone()));
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    fn set_cookies(response: &Response) -> Vec<Cookie<'static>> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| Cookie::parse_encoded(value.to_str().ok()?.to_owned()).ok())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_removes_the_refresh_cookie() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        let response = request_me(&auth_client, "refresh=revoked").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let cookies = set_cookies(&response);
+        let removal = cookies.iter().find(|c| c.name() == REFRESH_COOKIE).unwrap();
+        assert_eq!(removal.value(), "");
+        assert_eq!(removal.max_age(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn successful_refresh_sets_both_cookies() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        let response = request_me(&auth_client, "refresh=r-alice").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cookies = set_cookies(&response);
+        let auth = cookies.iter().find(|c| c.name() == AUTH_COOKIE).unwrap();
+        assert!(auth.value().contains("alice~refreshed"));
+        let refresh = cookies.iter().find(|c| c.name() == REFRESH_COOKIE).unwrap();
+        assert_eq!(refresh.value(), "r-alice~rotated");
+    }
+
+    #[tokio::test]
+    async fn me_reports_the_introspected_expiry() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        // The mock IdP introspects tokens as expiring an hour after the request
+        let before = OffsetDateTime::now_utc().unix_timestamp() + 3600;
+        let response = request_me(&auth_client, "refresh=r-alice").await;
+        let after = OffsetDateTime::now_utc().unix_timestamp() + 3600;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let header = response.headers()[X_TOKEN_EXPIRES_AT]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let me: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(me["expires_at"], header.as_str());
+
+        let expiry = OffsetDateTime::parse(&header, &Rfc3339)
+            .unwrap()
+            .unix_timestamp();
+        assert!((before..=after).contains(&expiry));
+    }
+
+    fn location(response: &Response) -> &str {
+        response.headers()[LOCATION].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn pending_cookie_only_holds_the_session_handle() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let (session, _, _) = oidc::tests::login(&auth_client, "alice");
+
+        // PKCE verifier and nonce stay on the server, the callback tests cover the rest of the flow
+        let pending = AuthState::Pending(session).cookie(false);
+        assert!(pending.value().len() < 64, "{}", pending.value());
+    }
+
+    #[tokio::test]
+    async fn repeated_callbacks_land_on_success() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let (session, code, state) = oidc::tests::login(&auth_client, "alice");
+
+        let pending = AuthState::Pending(session).cookie(false);
+        let cookie = format!("{}={}", pending.name(), pending.value());
+        let uri = format!("/callback?code={code}&state={}", state.secret());
+
+        let (first, second) = tokio::join!(
+            request(&auth_client, &uri, &cookie),
+            request(&auth_client, &uri, &cookie)
+        );
+        let third = request(&auth_client, &uri, &cookie).await;
+
+        for response in [first, second, third] {
+            assert_eq!(location(&response), "./success");
+
+            let cookies = set_cookies(&response);
+            let auth = cookies.iter().find(|c| c.name() == AUTH_COOKIE).unwrap();
+            assert!(auth.value().contains("alice~issued"));
+            assert!(cookies.iter().any(|c| c.name() == REFRESH_COOKIE));
         }
     }
+
+    #[tokio::test]
+    async fn callback_after_login_completed_lands_on_success() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        let authenticated = AuthState::Authenticated(AccessToken::new("alice~issued".into()));
+        let cookie = authenticated.cookie(false);
+        let cookie = format!("{}={}", cookie.name(), cookie.value());
+
+        let response = request(&auth_client, "/callback?code=alice:x&state=x", &cookie).await;
+        assert_eq!(location(&response), "./success");
+    }
+
+    #[tokio::test]
+    async fn callback_for_an_unknown_session_restarts_the_login() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let (session, code, state) = oidc::tests::login(&auth_client, "alice");
+
+        let unknown: oidc::AuthSession = serde_json::from_str("\"unknown\"").unwrap();
+        let unknown = AuthState::Pending(unknown).cookie(false);
+        let cookie = format!("{}={}", unknown.name(), unknown.value());
+        let uri = format!("/callback?code={code}&state={}", state.secret());
+
+        let response = request(&auth_client, &uri, &cookie).await;
+        assert_eq!(location(&response), "./login");
+        assert!(auth_client.knows_session(&session));
+    }
+
+    #[tokio::test]
+    async fn regular_login_drops_a_stale_popup_cookie() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        let response = request(&auth_client, "/login", &format!("{POPUP_COOKIE}=true")).await;
+        let cookies = set_cookies(&response);
+        let popup = cookies.iter().find(|c| c.name() == POPUP_COOKIE).unwrap();
+        assert_eq!(popup.value(), "");
+        assert_eq!(popup.max_age(), Some(Duration::ZERO));
+
+        let response = request(&auth_client, "/login?mode=popup", "").await;
+        let cookies = set_cookies(&response);
+        let popup = cookies.iter().find(|c| c.name() == POPUP_COOKIE).unwrap();
+        assert_eq!(popup.value(), "true");
+    }
+
+    #[tokio::test]
+    async fn popup_logins_report_back_to_the_opener() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        for (page, result) in [("/success", "success"), ("/failed", "failed")] {
+            let response = request(&auth_client, page, &format!("{POPUP_COOKIE}=true")).await;
+            assert!(set_cookies(&response)
+                .iter()
+                .any(|c| c.name() == POPUP_COOKIE && c.value().is_empty()));
+
+            let html = String::from_utf8(body(response).await).unwrap();
+            assert!(html.contains(&format!(
+                "window.opener.postMessage({{ login: \"{result}\" }}, window.location.origin)"
+            )));
+            assert!(html.contains("window.close()"));
+        }
+
+        let response = request(&auth_client, "/success", "").await;
+        assert_eq!(body(response).await, b"Login successful.");
+    }
+
+    #[tokio::test]
+    async fn logout_redirects_to_the_end_session_endpoint() {
+        let authenticated = AuthState::Authenticated(AccessToken::new("alice~issued".into()));
+        let cookie = authenticated.cookie(false);
+        let cookie = format!("{}={}", cookie.name(), cookie.value());
+
+        let idp = oidc::tests::mock_idp_with_end_session().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let response = request(&auth_client, "/logout", &cookie).await;
+
+        let destination = Url::parse(location(&response)).unwrap();
+        assert_eq!(
+            destination.as_str().split('?').next(),
+            Some(format!("{}logout", idp.issuer_url.as_str()).as_str())
+        );
+        let query: HashMap<_, _> = destination.query_pairs().collect();
+        assert_eq!(query["post_logout_redirect_uri"], "http://127.0.0.1/");
+        assert_eq!(query["client_id"], "client");
+
+        // Without an advertised endpoint only the local session ends
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let response = request(&auth_client, "/logout", &cookie).await;
+        assert_eq!(location(&response), "/");
+    }
+
+    #[test]
+    fn only_short_local_referrers_are_kept() {
+        let origin = Url::parse("https://jrnl.example.com/auth/callback").unwrap();
+
+        assert_eq!(
+            local_path("https://jrnl.example.com/entries/1?view=full#top", &origin).as_deref(),
+            Some("/entries/1?view=full#top")
+        );
+        assert_eq!(
+            local_path("https://evil.example.com/entries", &origin),
+            None
+        );
+        assert_eq!(local_path("http://jrnl.example.com/entries", &origin), None);
+        assert_eq!(local_path("not a url", &origin), None);
+
+        let long = format!("https://jrnl.example.com/{}", "a".repeat(MAX_REFERRER_LEN));
+        assert_eq!(local_path(&long, &origin), None);
+    }
+
+    #[tokio::test]
+    async fn login_drops_external_referrers() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+
+        let login = |referrer: String| {
+            let request = Request::builder()
+                .uri("/login")
+                .header(REFERER, referrer)
+                .body(Body::empty())
+                .unwrap();
+            send(&auth_client, request)
+        };
+        let redirect = |response: &Response| {
+            set_cookies(response)
+                .into_iter()
+                .find(|c| c.name() == REDIRECT_COOKIE)
+                .map(|c| c.value().to_owned())
+        };
+
+        let response = login("http://127.0.0.1/entries/1".into()).await;
+        assert_eq!(redirect(&response).as_deref(), Some("/entries/1"));
+
+        let response = login("https://evil.example.com/".into()).await;
+        assert_eq!(redirect(&response), None);
+
+        let response = login(format!("http://127.0.0.1/{}", "a".repeat(MAX_REFERRER_LEN))).await;
+        assert_eq!(redirect(&response), None);
+    }
 }
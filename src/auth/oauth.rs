@@ -5,7 +5,7 @@ use openidconnect::{
 use serde::Deserialize;
 use std::future::Future;
 
-const OAUTH_CONFIG_URL_SUFFIX: &'static str = ".well-known/oauth-authorization-server";
+const OAUTH_CONFIG_URL_SUFFIX: &str = ".well-known/oauth-authorization-server";
 
 #[derive(Debug, Deserialize)]
 pub struct OAuthProviderMetadata {
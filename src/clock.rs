@@ -0,0 +1,73 @@
+use crate::storage::StorageConfig;
+use axum::{
+    body::Body, http::header::CACHE_CONTROL, response::IntoResponse, routing::get, Extension, Json,
+    Router,
+};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use time_tz::{OffsetDateTimeExt, TimeZone};
+
+// Document identifiers are unix timestamps in this unit
+const ID_PRECISION: &str = "milliseconds";
+
+/// The server clock, so clients generating identifiers can correct for their own skew
+#[derive(Serialize)]
+struct ServerTime {
+    // Milliseconds like identifiers, so the value can be compared against them directly
+    now_unix: i64,
+    now_iso: String,
+    timezone: &'static str,
+    id_precision: &'static str,
+}
+
+pub fn router() -> Router<(), Body> {
+    Router::new().route("/time", get(time))
+}
+
+async fn time(Extension(config): Extension<StorageConfig>) -> impl IntoResponse {
+    let now = OffsetDateTime::now_utc();
+
+    (
+        // Stale clock readings are worse than none
+        [(CACHE_CONTROL, "no-store")],
+        Json(ServerTime {
+            now_unix: (now.unix_timestamp_nanos() / 1_000_000) as i64,
+            now_iso: now
+                .to_timezone(config.timezone)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            timezone: config.timezone.name(),
+            id_precision: ID_PRECISION,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::tests::body, storage::tests::config};
+
+    #[tokio::test]
+    async fn reports_the_configured_timezone_and_current_time() {
+        let config = StorageConfig {
+            timezone: time_tz::timezones::get_by_name("Europe/Berlin").unwrap(),
+            ..config()
+        };
+
+        let response = time(Extension(config)).await.into_response();
+        let reported: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        let now = (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64;
+
+        assert_eq!(reported["timezone"], "Europe/Berlin");
+        assert_eq!(reported["id_precision"], "milliseconds");
+
+        let now_unix = reported["now_unix"].as_i64().unwrap();
+        assert!((now - now_unix).abs() < 5_000);
+
+        let now_iso = OffsetDateTime::parse(reported["now_iso"].as_str().unwrap(), &Rfc3339);
+        assert_eq!(
+            now_iso.unwrap().unix_timestamp_nanos() / 1_000_000,
+            now_unix as i128
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    api::{
+        X_APPEND_SEQUENCE, X_CONTENT_SHA256, X_DOCUMENT_CREATED, X_DOCUMENT_DATE,
+        X_DOCUMENT_MODIFIED, X_DOCUMENT_WEEKDAY, X_NEXT_BEFORE, X_SIZE_WARNING, X_SOURCE_ENCODING,
+        X_TOTAL_COUNT,
+    },
+    auth::X_TOKEN_EXPIRES_AT,
+};
+use axum::http::{
+    header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
+    HeaderName, HeaderValue,
+};
+use std::time::Duration;
+use tower_http::cors::{AllowMethods, AllowOrigin, CorsLayer};
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+// Credentials (i.e. the auth cookie) are only sent cross-origin to an explicit list of origins
+pub fn layer(allowed_origins: Vec<HeaderValue>, max_age: Duration) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(allowed_origins))
+        .allow_credentials(true)
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers([
+            CONTENT_TYPE,
+            IF_MATCH,
+            IF_NONE_MATCH,
+            IDEMPOTENCY_KEY,
+            X_APPEND_SEQUENCE,
+        ])
+        .expose_headers([
+            ETAG,
+            X_TOTAL_COUNT,
+            X_NEXT_BEFORE,
+            X_SOURCE_ENCODING,
+            X_CONTENT_SHA256,
+            X_DOCUMENT_DATE,
+            X_DOCUMENT_CREATED,
+            X_DOCUMENT_MODIFIED,
+            X_SIZE_WARNING,
+            X_DOCUMENT_WEEKDAY,
+            X_TOKEN_EXPIRES_AT,
+        ])
+        .max_age(max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{
+            header::{
+                ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+                ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+                ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+                ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+            },
+            HeaderMap, Method, Request,
+        },
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    const ALLOWED: &str = "https://app.example.com";
+
+    async fn send(method: Method, origin: &str) -> HeaderMap {
+        let app = Router::new()
+            .route("/api/document", get(|| async {}))
+            .layer(layer(
+                vec![HeaderValue::from_static(ALLOWED)],
+                Duration::from_secs(600),
+            ));
+
+        let request = Request::builder()
+            .method(method)
+            .uri("/api/document")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type,if-match")
+            .body(Body::empty())
+            .unwrap();
+
+        app.oneshot(request).await.unwrap().headers().clone()
+    }
+
+    fn header(headers: &HeaderMap, name: HeaderName) -> &str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_the_configured_origin() {
+        let headers = send(Method::OPTIONS, ALLOWED).await;
+
+        assert_eq!(header(&headers, ACCESS_CONTROL_ALLOW_ORIGIN), ALLOWED);
+        assert_eq!(header(&headers, ACCESS_CONTROL_ALLOW_CREDENTIALS), "true");
+        assert_eq!(header(&headers, ACCESS_CONTROL_ALLOW_METHODS), "PUT");
+        assert_eq!(header(&headers, ACCESS_CONTROL_MAX_AGE), "600");
+
+        let allowed = header(&headers, ACCESS_CONTROL_ALLOW_HEADERS);
+        assert!(allowed.contains("content-type") && allowed.contains("if-match"));
+    }
+
+    #[tokio::test]
+    async fn responses_expose_the_custom_headers() {
+        let headers = send(Method::GET, ALLOWED).await;
+
+        let exposed = header(&headers, ACCESS_CONTROL_EXPOSE_HEADERS);
+        for name in [ETAG, X_TOTAL_COUNT, X_CONTENT_SHA256, X_TOKEN_EXPIRES_AT] {
+            assert!(exposed.contains(name.as_str()), "{name} is not exposed");
+        }
+    }
+
+    #[tokio::test]
+    async fn other_origins_are_not_allowed() {
+        let headers = send(Method::OPTIONS, "https://evil.example.com").await;
+        assert!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
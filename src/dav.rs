@@ -0,0 +1,289 @@
+//! Minimal WebDAV subset for mounting the journal in a file manager. Documents appear as
+//! `<identifier>.md` files in a single flat collection. Supported are `OPTIONS`, `PROPFIND` with
+//! depth 0 or 1, `GET`/`HEAD` of documents, `PUT` creating or replacing them and `DELETE`. There
+//! are no locks, no `MOVE` or `COPY`, and no subcollections. Authentication is the same as for
+//! the API.
+
+use crate::{
+    api,
+    storage::{Document, DocumentIdentifier, DocumentMetadata, UserStorage},
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{OriginalUri, Path},
+    http::{
+        header::{ALLOW, CONTENT_TYPE},
+        HeaderMap, HeaderName, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use std::{fmt::Write, time::SystemTime};
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+use tokio::io::ErrorKind;
+use tracing::warn;
+
+const DAV: HeaderName = HeaderName::from_static("dav");
+const DEPTH: HeaderName = HeaderName::from_static("depth");
+
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE";
+const CONTENT_TYPE_XML: &str = "application/xml; charset=utf-8";
+const CONTENT_TYPE_MARKDOWN: &str = "text/markdown; charset=utf-8";
+
+// RFC 1123 as required for `getlastmodified`
+const HTTP_DATE: &[FormatItem<'_>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+// Merged instead of nested, nesting wouldn't match the trailing slash clients use for collections
+pub fn router() -> Router<(), Body> {
+    Router::new()
+        .route("/dav", any(collection))
+        .route("/dav/", any(collection))
+        .route("/dav/:file", any(file))
+}
+
+async fn collection(
+    method: Method,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    storage: UserStorage,
+) -> Result<Response, StatusCode> {
+    match method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => {
+            let href = format!("{}/", uri.path().trim_end_matches('/'));
+            let mut responses = collection_response(&href);
+
+            // Infinite depth isn't supported, but there is nothing below the documents anyway
+            if headers.get(DEPTH).is_none_or(|depth| depth != "0") {
+                let listing = storage.list().await.map_err(internal_error)?;
+
+                for metadata in listing {
+                    let file_href = format!("{href}{}", metadata.identifier.file_name());
+                    responses.push_str(&document_response(&file_href, &metadata));
+                }
+            }
+
+            Ok(multistatus(responses))
+        }
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+async fn file(
+    Path(name): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    storage: UserStorage,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    let identifier = DocumentIdentifier::from_file_name(&name).ok_or(StatusCode::NOT_FOUND)?;
+
+    match method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => {
+            let metadata = storage
+                .list()
+                .await
+                .map_err(internal_error)?
+                .into_iter()
+                .find(|m| m.identifier == identifier)
+                .ok_or(StatusCode::NOT_FOUND)?;
+
+            Ok(multistatus(document_response(uri.path(), &metadata)))
+        }
+        "GET" | "HEAD" => {
+            let contents = storage
+                .read_decoded(identifier)
+                .await
+                .map_err(|e| match e.kind() {
+                    ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    _ => internal_error(e),
+                })?;
+
+            let mut response = api::decoded_response(contents);
+            if response.headers().get(CONTENT_TYPE).is_none() {
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    CONTENT_TYPE_MARKDOWN.parse().expect("valid header value"),
+                );
+            }
+
+            Ok(response)
+        }
+        "PUT" => {
+            let contents =
+                String::from_utf8(body.to_vec()).map_err(|_| StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+            let existed = storage.exists(identifier).await.map_err(internal_error)?;
+
+            match storage.write(Document::new(identifier, contents)).await {
+                Ok(_) if existed => Ok(StatusCode::NO_CONTENT.into_response()),
+                Ok(_) => Ok(StatusCode::CREATED.into_response()),
+                // Past the maximum age
+                Err(e) if e.kind() == ErrorKind::NotFound => Err(StatusCode::NOT_FOUND),
+                Err(e) => Err(api::rejection(&e).unwrap_or_else(|| internal_error(e))),
+            }
+        }
+        "DELETE" => match storage.delete(identifier).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(StatusCode::NOT_FOUND),
+            Err(e) => Err(internal_error(e)),
+        },
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+fn options() -> Response {
+    ([(DAV, "1"), (ALLOW, ALLOWED_METHODS)], StatusCode::OK).into_response()
+}
+
+fn method_not_allowed() -> Response {
+    ([(ALLOW, ALLOWED_METHODS)], StatusCode::METHOD_NOT_ALLOWED).into_response()
+}
+
+fn internal_error(e: std::io::Error) -> StatusCode {
+    warn!("Failed to serve WebDAV request: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+fn multistatus(responses: String) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:multistatus xmlns:D=\"DAV:\">{responses}</D:multistatus>\n"
+    );
+
+    (
+        StatusCode::MULTI_STATUS,
+        [(CONTENT_TYPE, CONTENT_TYPE_XML)],
+        body,
+    )
+        .into_response()
+}
+
+// Hrefs are built from the request path and file names made of digits, neither needs escaping
+fn collection_response(href: &str) -> String {
+    format!(
+        "<D:response><D:href>{href}</D:href><D:propstat><D:prop>\
+         <D:resourcetype><D:collection/></D:resourcetype>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+    )
+}
+
+fn document_response(href: &str, metadata: &DocumentMetadata) -> String {
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname><D:resourcetype/>\
+         <D:getcontentlength>{}</D:getcontentlength>\
+         <D:getcontenttype>{CONTENT_TYPE_MARKDOWN}</D:getcontenttype>",
+        metadata.identifier.file_name(),
+        metadata.size
+    );
+
+    if let Some(modified) = metadata.modified.and_then(http_date) {
+        let _ = write!(props, "<D:getlastmodified>{modified}</D:getlastmodified>");
+    }
+
+    format!(
+        "<D:response><D:href>{href}</D:href><D:propstat><D:prop>{props}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+    )
+}
+
+fn http_date(time: SystemTime) -> Option<String> {
+    OffsetDateTime::from(time).format(HTTP_DATE).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::oidc,
+        storage::{tests::config, StorageConfig, StorageState},
+    };
+    use axum::{http::Request, Extension};
+    use tower::ServiceExt;
+
+    async fn send(
+        auth_client: &oidc::AuthClient,
+        config: &StorageConfig,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> Response {
+        let app = router()
+            .layer(Extension(auth_client.clone()))
+            .layer(Extension(config.clone()))
+            .layer(Extension(StorageState::default()));
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("cookie", "refresh=r-alice")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        String::from_utf8(api::tests::body(response).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn propfind_lists_documents() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "alice");
+        let identifier = storage.create("Entry".into()).await.unwrap();
+        let size = storage.list().await.unwrap()[0].size;
+
+        let response = send(&auth_client, &config, "PROPFIND", "/dav/", "").await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+
+        let xml = body(response).await;
+        let name = identifier.file_name();
+        assert!(xml
+            .contains("<D:href>/dav/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/>"));
+        assert!(xml.contains(&format!("<D:href>/dav/{name}</D:href>")));
+        assert!(xml.contains(&format!("<D:getcontentlength>{size}</D:getcontentlength>")));
+    }
+
+    #[tokio::test]
+    async fn put_creates_and_replaces_documents() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "alice");
+        let identifier = storage.create("Entry".into()).await.unwrap();
+        let uri = format!("/dav/{}", identifier.file_name());
+
+        let response = send(&auth_client, &config, "PUT", &uri, "Replaced\n").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = send(&auth_client, &config, "GET", &uri, "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "Replaced\n");
+
+        let response = send(&auth_client, &config, "GET", "/dav/..%2Fsecret.md", "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_documents() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let config = config();
+        let storage = UserStorage::new(config.clone(), StorageState::default(), "alice");
+        let identifier = storage.create("Entry".into()).await.unwrap();
+        let uri = format!("/dav/{}", identifier.file_name());
+
+        let response = send(&auth_client, &config, "DELETE", &uri, "").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(storage.list().await.unwrap().is_empty());
+
+        let response = send(&auth_client, &config, "DELETE", &uri, "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,7 +1,8 @@
+use std::path::Path;
 use tower_http::services::{ServeDir, ServeFile};
 
-pub fn service() -> ServeDir<ServeFile> {
-    ServeDir::new("./frontend/build")
+pub fn service(dir: &Path) -> ServeDir<ServeFile> {
+    ServeDir::new(dir)
         .append_index_html_on_directories(true)
-        .fallback(ServeFile::new("./frontend/build/index.html"))
+        .fallback(ServeFile::new(dir.join("index.html")))
 }
@@ -0,0 +1,154 @@
+use git2::{IndexAddOption, Repository, Signature};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task,
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+const AUTHOR_NAME: &str = "jrnl";
+const AUTHOR_EMAIL: &str = "jrnl@localhost";
+
+pub struct Change {
+    pub subject: String,
+    pub action: &'static str,
+    pub target: String,
+}
+
+/// Commits changes to the storage root, batching everything that happens in quick succession
+#[derive(Clone)]
+pub struct GitCommitter {
+    sender: UnboundedSender<Change>,
+}
+
+impl GitCommitter {
+    pub fn spawn(root: PathBuf, debounce: Duration) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(root, debounce, receiver));
+        Self { sender }
+    }
+
+    pub fn record(&self, change: Change) {
+        if self.sender.send(change).is_err() {
+            warn!("Git committer is no longer running, change will not be committed");
+        }
+    }
+}
+
+async fn run(root: PathBuf, debounce: Duration, mut receiver: UnboundedReceiver<Change>) {
+    while let Some(change) = receiver.recv().await {
+        let mut changes = vec![change];
+
+        // Keep collecting until nothing has happened for a full debounce period
+        while let Ok(Some(change)) = timeout(debounce, receiver.recv()).await {
+            changes.push(change);
+        }
+
+        let root = root.clone();
+        match task::spawn_blocking(move || commit(&root, &changes)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Failed to commit storage changes: {err}"),
+            Err(err) => warn!("Git commit task failed: {err}"),
+        }
+    }
+}
+
+fn commit(root: &Path, changes: &[Change]) -> Result<(), git2::Error> {
+    let repository = match Repository::open(root) {
+        Ok(repository) => repository,
+        Err(_) => Repository::init(root)?,
+    };
+
+    let mut index = repository.index()?;
+    index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+    index.update_all(["*"], None)?;
+    index.write()?;
+
+    let tree = repository.find_tree(index.write_tree()?)?;
+    let parent = repository.head().ok().and_then(|h| h.peel_to_commit().ok());
+
+    if parent.as_ref().is_some_and(|p| p.tree_id() == tree.id()) {
+        debug!("Skipping git commit as the storage is unchanged");
+        return Ok(());
+    }
+
+    let summary = match changes {
+        [change] => format!("{} {} {}", change.subject, change.action, change.target),
+        _ => format!("{} changes", changes.len()),
+    };
+
+    let details: String = changes
+        .iter()
+        .map(|c| format!("\n{}: {} {}", c.subject, c.action, c.target))
+        .collect();
+
+    let message = if changes.len() > 1 {
+        format!("{summary}\n{details}")
+    } else {
+        summary
+    };
+
+    let signature = Signature::now(AUTHOR_NAME, AUTHOR_EMAIL)?;
+    let parents: Vec<_> = parent.iter().collect();
+
+    repository.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &parents,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metrics::Metrics,
+        storage::{
+            tests::{config, document},
+            StorageState, UserStorage,
+        },
+    };
+
+    #[tokio::test]
+    async fn writes_are_committed() {
+        let config = config();
+        let committer = GitCommitter::spawn(config.location.clone(), Duration::from_millis(10));
+        let state = StorageState::new(Some(committer), Metrics::default());
+
+        UserStorage::new(config.clone(), state, "alice")
+            .write(document(1, "committed"))
+            .await
+            .unwrap();
+
+        let mut message = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let head = Repository::open(&config.location)
+                .ok()
+                .and_then(|repository| {
+                    let commit = repository.head().ok()?.peel_to_commit().ok()?;
+                    let tree = commit.tree().ok()?;
+                    tree.get_path(Path::new("alice/1.md")).ok()?;
+                    commit.message().map(ToOwned::to_owned)
+                });
+
+            if head.is_some() {
+                message = head;
+                break;
+            }
+        }
+
+        assert_eq!(message.as_deref(), Some("alice write 1"));
+        std::fs::remove_dir_all(&config.location).unwrap();
+    }
+}
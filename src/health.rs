@@ -0,0 +1,49 @@
+use crate::{auth::oidc::AuthClient, storage::StorageConfig};
+use axum::{body::Body, http::StatusCode, routing::get, Extension, Json, Router};
+use serde_json::{json, Value};
+use tokio::fs;
+use tracing::warn;
+
+const READINESS_PROBE_FILE: &str = ".ready";
+
+pub fn router() -> Router<(), Body> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+}
+
+async fn health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// The auth client only exists once OIDC discovery succeeded, so only storage needs checking
+async fn ready(
+    auth_client: Option<Extension<AuthClient>>,
+    Extension(config): Extension<StorageConfig>,
+) -> (StatusCode, Json<Value>) {
+    if auth_client.is_none() {
+        return unavailable("authentication is not set up");
+    }
+
+    let probe = config.location.join(READINESS_PROBE_FILE);
+    let writable = async {
+        fs::create_dir_all(&config.location).await?;
+        fs::write(&probe, b"").await?;
+        fs::remove_file(&probe).await
+    }
+    .await;
+
+    if let Err(e) = writable {
+        warn!("Readiness check failed, storage is not writable: {e}");
+        return unavailable("storage is not writable");
+    }
+
+    (StatusCode::OK, Json(json!({ "status": "ready" })))
+}
+
+fn unavailable(reason: &str) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({ "status": "unavailable", "reason": reason })),
+    )
+}
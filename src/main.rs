@@ -1,57 +1,160 @@
-use axum::{Extension, Router};
+use axum::{extract::DefaultBodyLimit, http::HeaderValue, middleware, Extension, Router};
+use encoding_rs::Encoding;
 use openidconnect::{ClientId, ClientSecret, IssuerUrl, RedirectUrl, Scope};
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use tower_http::{
+    trace::{DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::Level;
 
+mod admin;
 mod api;
 mod auth;
+mod clock;
+mod cors;
+mod dav;
 mod frontend;
+mod git;
+mod health;
+mod maintenance;
+mod markdown;
+mod metrics;
 mod storage;
+mod trace;
 
-const ENV_STORAGE_LOCATION: &'static str = "THOUGHT_STORAGE_LOCATION";
-const ENV_OIDC_ISSUER: &'static str = "THOUGHT_OIDC_ISSUER_URL";
-const ENV_OIDC_REDIRECT_URL: &'static str = "THOUGHT_OIDC_REDIRECT_URL";
-const ENV_OIDC_CLIENT_ID: &'static str = "THOUGHT_OIDC_CLIENT_ID";
-const ENV_OIDC_CLIENT_SECRET: &'static str = "THOUGHT_OIDC_CLIENT_SECRET";
-const ENV_OIDC_SCOPES: &'static str = "THOUGHT_OIDC_SCOPES";
-const ENV_OIDC_GROUPS: &'static str = "THOUGHT_OIDC_GROUPS";
+const ENV_BIND_ADDR: &str = "THOUGHT_BIND_ADDR";
+const ENV_METRICS_BIND_ADDR: &str = "THOUGHT_METRICS_BIND_ADDR";
+const ENV_STORAGE_LOCATION: &str = "THOUGHT_STORAGE_LOCATION";
+const ENV_STORAGE_MIRROR: &str = "THOUGHT_STORAGE_MIRROR";
+const ENV_OIDC_ISSUER: &str = "THOUGHT_OIDC_ISSUER_URL";
+const ENV_OIDC_REDIRECT_URL: &str = "THOUGHT_OIDC_REDIRECT_URL";
+const ENV_OIDC_CLIENT_ID: &str = "THOUGHT_OIDC_CLIENT_ID";
+const ENV_OIDC_CLIENT_SECRET: &str = "THOUGHT_OIDC_CLIENT_SECRET";
+const ENV_OIDC_SCOPES: &str = "THOUGHT_OIDC_SCOPES";
+const ENV_OIDC_GROUPS: &str = "THOUGHT_OIDC_GROUPS";
+const ENV_OIDC_GROUPS_MODE: &str = "THOUGHT_OIDC_GROUPS_MODE";
+const ENV_OIDC_PROMPT: &str = "THOUGHT_OIDC_PROMPT";
+const ENV_OIDC_ACR_VALUES: &str = "THOUGHT_OIDC_ACR_VALUES";
+const ENV_LOGIN_RATE_LIMIT: &str = "THOUGHT_LOGIN_RATE_LIMIT";
+const ENV_TRUST_FORWARDED_FOR: &str = "THOUGHT_TRUST_FORWARDED_FOR";
+const ENV_GLOBAL_LOGOUT: &str = "THOUGHT_GLOBAL_LOGOUT";
+const ENV_DISABLE_INTROSPECTION_CACHE: &str = "THOUGHT_DISABLE_INTROSPECTION_CACHE";
+const ENV_REQUIRE_HTTPS: &str = "THOUGHT_REQUIRE_HTTPS";
+const ENV_ALLOWED_ORIGINS: &str = "THOUGHT_ALLOWED_ORIGINS";
+const ENV_CORS_MAX_AGE: &str = "THOUGHT_CORS_MAX_AGE";
+const ENV_FALLBACK_ENCODING: &str = "THOUGHT_FALLBACK_ENCODING";
+const ENV_ADMIN_TOKEN: &str = "THOUGHT_ADMIN_TOKEN";
+const ENV_TRIM_TRAILING_WHITESPACE: &str = "THOUGHT_TRIM_TRAILING_WHITESPACE";
+const ENV_ENSURE_FINAL_NEWLINE: &str = "THOUGHT_ENSURE_FINAL_NEWLINE";
+const ENV_MAX_LISTING: &str = "THOUGHT_MAX_LISTING";
+const ENV_REJECT_BINARY: &str = "THOUGHT_REJECT_BINARY";
+const ENV_BINARY_THRESHOLD: &str = "THOUGHT_BINARY_THRESHOLD";
+const ENV_TIMEZONE: &str = "THOUGHT_TIMEZONE";
+const ENV_MAX_PREVIEW_LEN: &str = "THOUGHT_MAX_PREVIEW_LEN";
+const ENV_GIT_STORAGE: &str = "THOUGHT_GIT_STORAGE";
+const ENV_GIT_DEBOUNCE: &str = "THOUGHT_GIT_DEBOUNCE";
+const ENV_READ_CONCURRENCY: &str = "THOUGHT_READ_CONCURRENCY";
+const ENV_MAX_CONCURRENT_PER_USER: &str = "THOUGHT_MAX_CONCURRENT_PER_USER";
+const ENV_TEMPLATES_DIR: &str = "THOUGHT_TEMPLATES_DIR";
+const ENV_FSYNC: &str = "THOUGHT_FSYNC";
+const ENV_MAX_DOCUMENT_BYTES: &str = "THOUGHT_MAX_DOCUMENT_BYTES";
+const ENV_MAX_TAGS_PER_DOCUMENT: &str = "THOUGHT_MAX_TAGS_PER_DOCUMENT";
+const ENV_TAG_LIMIT_MODE: &str = "THOUGHT_TAG_LIMIT_MODE";
+const ENV_LARGE_DOCUMENT_BYTES: &str = "THOUGHT_LARGE_DOCUMENT_BYTES";
+const ENV_AUTOSPLIT_BYTES: &str = "THOUGHT_AUTOSPLIT_BYTES";
+const ENV_ID_AS_STRING: &str = "THOUGHT_ID_AS_STRING";
+const ENV_GATED_FEATURES: &str = "THOUGHT_GATED_FEATURES";
+const ENV_WELCOME_ENTRY: &str = "THOUGHT_WELCOME_ENTRY";
+const ENV_WELCOME_ENTRY_FILE: &str = "THOUGHT_WELCOME_ENTRY_FILE";
+const ENV_REQUEST_LOG_LEVEL: &str = "THOUGHT_REQUEST_LOG_LEVEL";
+const ENV_FRONTEND_DIR: &str = "THOUGHT_FRONTEND_DIR";
+const ENV_MAX_ENTRY_AGE_DAYS: &str = "THOUGHT_MAX_ENTRY_AGE_DAYS";
+const ENV_SHARE_SECRET: &str = "THOUGHT_SHARE_SECRET";
+const ENV_METRICS_SCAN_INTERVAL_SECS: &str = "THOUGHT_METRICS_SCAN_INTERVAL_SECS";
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 60 * 60;
+const DEFAULT_LOGIN_RATE_LIMIT: u32 = 30;
+const DEFAULT_MAX_LISTING: usize = 500;
+const DEFAULT_MAX_DOCUMENT_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_TAGS_PER_DOCUMENT: usize = 50;
+const DEFAULT_BINARY_THRESHOLD: f64 = 0.1;
+const DEFAULT_TIMEZONE: &str = "UTC";
+const DEFAULT_MAX_PREVIEW_LEN: usize = 16 * 1024;
+const DEFAULT_READ_CONCURRENCY: usize = 16;
+const DEFAULT_GIT_DEBOUNCE_SECS: u64 = 10;
+const DEFAULT_METRICS_SCAN_INTERVAL_SECS: u64 = 5 * 60;
+const DEFAULT_REQUEST_LOG_LEVEL: Level = Level::INFO;
+const DEFAULT_FRONTEND_DIR: &str = "./frontend/build";
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let issuer_url = IssuerUrl::new(
-        env::var(ENV_OIDC_ISSUER).expect(&format!("env var {ENV_OIDC_ISSUER} not set")),
-    )
-    .expect("invalid oidc issuer url");
+    // Parsed first so a typo fails right away instead of after OIDC discovery
+    let bind_addr = env::var(ENV_BIND_ADDR).unwrap_or_else(|_| DEFAULT_BIND_ADDR.into());
+    let addr = SocketAddr::from_str(&bind_addr)
+        .unwrap_or_else(|e| panic!("invalid bind address {bind_addr} (expected host:port): {e}"));
 
-    let redirect_url = RedirectUrl::new(
-        env::var(ENV_OIDC_REDIRECT_URL).expect(&format!("env var {ENV_OIDC_REDIRECT_URL} not set")),
-    )
-    .expect("invalid oidc redirect url");
+    // Metrics are unauthenticated, so they can be kept off the public listener
+    let metrics_addr = env::var(ENV_METRICS_BIND_ADDR)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(|addr| {
+            SocketAddr::from_str(&addr).unwrap_or_else(|e| {
+                panic!("invalid metrics bind address {addr} (expected host:port): {e}")
+            })
+        });
 
-    let client_id = ClientId::new(
-        env::var(ENV_OIDC_CLIENT_ID).expect(&format!("env var {ENV_OIDC_CLIENT_ID} not set")),
-    );
+    let issuer_url =
+        IssuerUrl::new(required_env(ENV_OIDC_ISSUER)).expect("invalid oidc issuer url");
 
-    let client_secret = Some(ClientSecret::new(
-        env::var(ENV_OIDC_CLIENT_SECRET)
-            .expect(&format!("env var {ENV_OIDC_CLIENT_SECRET} not set")),
-    ));
+    let redirect_url =
+        RedirectUrl::new(required_env(ENV_OIDC_REDIRECT_URL)).expect("invalid oidc redirect url");
+
+    let client_id = ClientId::new(required_env(ENV_OIDC_CLIENT_ID));
+
+    let client_secret = Some(ClientSecret::new(required_env(ENV_OIDC_CLIENT_SECRET)));
 
     let scopes = env::var(ENV_OIDC_SCOPES)
         .unwrap_or_default()
-        .split(" ")
+        .split(' ')
         .filter(|s| !s.is_empty())
         .map(|s| Scope::new(s.to_owned()))
         .collect();
 
     let required_groups = env::var(ENV_OIDC_GROUPS)
         .unwrap_or_default()
-        .split(" ")
+        .split(' ')
         .filter(|s| !s.is_empty())
         .map(|s| s.to_owned())
         .collect();
 
+    let request_log_level = env::var(ENV_REQUEST_LOG_LEVEL)
+        .map(|s| s.parse().expect("invalid request log level"))
+        .unwrap_or(DEFAULT_REQUEST_LOG_LEVEL);
+
+    let acr_values = env::var(ENV_OIDC_ACR_VALUES)
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect();
+
+    let allowed_origins: Vec<HeaderValue> = env::var(ENV_ALLOWED_ORIGINS)
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .map(|s| HeaderValue::from_str(s).expect("invalid origin in allowed origins"))
+        .collect();
+
+    let cors_max_age = Duration::from_secs(
+        env::var(ENV_CORS_MAX_AGE)
+            .map(|s| s.parse().expect("invalid cors max age"))
+            .unwrap_or(DEFAULT_CORS_MAX_AGE_SECS),
+    );
+
     let auth_config = auth::oidc::AuthConfig {
         issuer_url,
         redirect_url,
@@ -62,20 +165,293 @@ async fn main() {
         scopes,
 
         required_groups,
+        groups_mode: env::var(ENV_OIDC_GROUPS_MODE)
+            .map(|s| s.parse().unwrap_or_else(|e| panic!("{e}")))
+            .unwrap_or_default(),
+
+        global_logout: env_flag(ENV_GLOBAL_LOGOUT, false),
+        cache_introspection: !env_flag(ENV_DISABLE_INTROSPECTION_CACHE, false),
+        require_https: env_flag(ENV_REQUIRE_HTTPS, true),
+
+        prompt: env::var(ENV_OIDC_PROMPT)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().unwrap_or_else(|e| panic!("{e}"))),
+        acr_values,
+
+        login_rate_limit: env::var(ENV_LOGIN_RATE_LIMIT)
+            .map(|s| s.parse().expect("invalid login rate limit"))
+            .unwrap_or(DEFAULT_LOGIN_RATE_LIMIT),
+        trust_forwarded_for: env_flag(ENV_TRUST_FORWARDED_FOR, false),
     };
 
-    let auth_client = auth::oidc::AuthClient::new(auth_config).await.unwrap();
+    let metrics = metrics::Metrics::default();
+
+    let auth_client = auth::oidc::AuthClient::new(auth_config, metrics.clone())
+        .await
+        .unwrap();
+
+    let fallback_encoding = env::var(ENV_FALLBACK_ENCODING).ok().map(|label| {
+        Encoding::for_label(label.as_bytes())
+            .unwrap_or_else(|| panic!("unknown fallback encoding {label}"))
+    });
+
+    let timezone_name = env::var(ENV_TIMEZONE).unwrap_or_else(|_| DEFAULT_TIMEZONE.into());
+    let timezone = time_tz::timezones::get_by_name(&timezone_name)
+        .unwrap_or_else(|| panic!("unknown timezone {timezone_name}"));
+
+    let gated_features = env::var(ENV_GATED_FEATURES)
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            assert!(
+                api::GATEABLE_FEATURES.contains(&s),
+                "unknown gated feature {s}, expected one of {:?}",
+                api::GATEABLE_FEATURES
+            );
+            s.to_owned()
+        })
+        .collect();
+
+    // A file is easier to maintain for longer entries, it takes precedence over the inline text
+    let welcome_entry = match env::var(ENV_WELCOME_ENTRY_FILE) {
+        Ok(path) => Some(
+            std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("unable to read welcome entry {path}: {e}")),
+        ),
+        Err(_) => env::var(ENV_WELCOME_ENTRY).ok(),
+    }
+    .filter(|entry| !entry.is_empty())
+    .map(Into::into);
+
+    storage::serialize_identifiers_as_strings(env_flag(ENV_ID_AS_STRING, false));
+
+    let storage_config = storage::StorageConfig {
+        location: required_env(ENV_STORAGE_LOCATION).into(),
+        fallback_encoding,
+        trim_trailing_whitespace: env_flag(ENV_TRIM_TRAILING_WHITESPACE, false),
+        ensure_final_newline: env_flag(ENV_ENSURE_FINAL_NEWLINE, false),
+        reject_binary_threshold: env_flag(ENV_REJECT_BINARY, false).then(|| {
+            env::var(ENV_BINARY_THRESHOLD)
+                .map(|s| s.parse().expect("invalid binary threshold"))
+                .unwrap_or(DEFAULT_BINARY_THRESHOLD)
+        }),
+        max_document_bytes: env::var(ENV_MAX_DOCUMENT_BYTES)
+            .map(|s| s.parse().expect("invalid max document size"))
+            .unwrap_or(DEFAULT_MAX_DOCUMENT_BYTES),
+        max_tags_per_document: env::var(ENV_MAX_TAGS_PER_DOCUMENT)
+            .map(|s| s.parse().expect("invalid max tags per document"))
+            .unwrap_or(DEFAULT_MAX_TAGS_PER_DOCUMENT),
+        tag_limit_mode: env::var(ENV_TAG_LIMIT_MODE)
+            .map(|s| s.parse().unwrap_or_else(|e| panic!("{e}")))
+            .unwrap_or_default(),
+        autosplit_bytes: env::var(ENV_AUTOSPLIT_BYTES)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().expect("invalid autosplit size")),
+        large_document_bytes: env::var(ENV_LARGE_DOCUMENT_BYTES)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().expect("invalid large document size")),
+        max_listing: env::var(ENV_MAX_LISTING)
+            .map(|s| s.parse().expect("invalid max listing size"))
+            .unwrap_or(DEFAULT_MAX_LISTING),
+        max_preview_len: env::var(ENV_MAX_PREVIEW_LEN)
+            .map(|s| s.parse().expect("invalid max preview length"))
+            .unwrap_or(DEFAULT_MAX_PREVIEW_LEN),
+        read_concurrency: env::var(ENV_READ_CONCURRENCY)
+            .map(|s| s.parse().expect("invalid read concurrency"))
+            .unwrap_or(DEFAULT_READ_CONCURRENCY)
+            .max(1),
+        timezone,
+        max_concurrent_per_user: env::var(ENV_MAX_CONCURRENT_PER_USER)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().expect("invalid max concurrent requests per user")),
+        templates_dir: env::var(ENV_TEMPLATES_DIR).ok().map(Into::into),
+        fsync: env::var(ENV_FSYNC)
+            .map(|s| s.parse().unwrap_or_else(|e| panic!("{e}")))
+            .unwrap_or_default(),
+        gated_features,
+        welcome_entry,
+        mirror: env::var(ENV_STORAGE_MIRROR)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(Into::into),
+        max_entry_age: env::var(ENV_MAX_ENTRY_AGE_DAYS)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<u64>().expect("invalid max entry age"))
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+    };
 
-    let app = Router::new()
-        .nest("/auth", auth::router())
-        .nest("/api", api::router())
-        .fallback_service(frontend::service())
-        .layer(Extension(auth_client));
+    let git_committer = env_flag(ENV_GIT_STORAGE, false).then(|| {
+        let debounce = env::var(ENV_GIT_DEBOUNCE)
+            .map(|s| s.parse().expect("invalid git debounce"))
+            .unwrap_or(DEFAULT_GIT_DEBOUNCE_SECS);
+
+        git::GitCommitter::spawn(
+            storage_config.location.clone(),
+            Duration::from_secs(debounce),
+        )
+    });
+
+    let storage_state = storage::StorageState::new(git_committer, metrics.clone());
+
+    let maintenance = maintenance::Maintenance::default();
+
+    tokio::spawn(storage::purge_expired_documents(
+        storage_config.clone(),
+        storage_state.clone(),
+        maintenance.clone(),
+    ));
+
+    let metrics_scan_interval = env::var(ENV_METRICS_SCAN_INTERVAL_SECS)
+        .map(|s| s.parse().expect("invalid metrics scan interval"))
+        .unwrap_or(DEFAULT_METRICS_SCAN_INTERVAL_SECS);
+
+    tokio::spawn(storage::count_documents_periodically(
+        storage_config.clone(),
+        storage_state.clone(),
+        Duration::from_secs(metrics_scan_interval.max(1)),
+    ));
+
+    let admin_config = admin::AdminConfig {
+        token: env::var(ENV_ADMIN_TOKEN).ok().filter(|t| !t.is_empty()),
+    };
+
+    let share_config = api::share::ShareConfig {
+        secret: env::var(ENV_SHARE_SECRET)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.into_bytes().into()),
+    };
+
+    let frontend_dir =
+        PathBuf::from(env::var(ENV_FRONTEND_DIR).unwrap_or_else(|_| DEFAULT_FRONTEND_DIR.into()));
+
+    if !frontend_dir.is_dir() {
+        panic!(
+            "frontend directory {} does not exist (set {ENV_FRONTEND_DIR})",
+            frontend_dir.display()
+        );
+    }
+
+    let mut app = Router::new()
+        .merge(health::router())
+        .merge(clock::router())
+        .nest(
+            "/auth",
+            auth::router().layer(middleware::from_fn(maintenance::guard)),
+        )
+        .nest(
+            "/api",
+            api::router()
+                .layer(middleware::from_fn(maintenance::guard))
+                // Axum's own limit would otherwise cap documents at 2 MB regardless of the config
+                .layer(DefaultBodyLimit::max(storage_config.max_document_bytes)),
+        )
+        .merge(
+            dav::router()
+                .layer(middleware::from_fn(maintenance::guard))
+                .layer(DefaultBodyLimit::max(storage_config.max_document_bytes)),
+        )
+        .nest("/admin", admin::router())
+        .fallback_service(frontend::service(&frontend_dir))
+        .layer(middleware::from_fn(auth::persist_refreshed_tokens))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(Extension(auth_client.clone()))
+        .layer(Extension(storage_config))
+        .layer(Extension(storage_state))
+        .layer(Extension(admin_config))
+        .layer(Extension(share_config))
+        .layer(Extension(maintenance))
+        .layer(Extension(metrics.clone()));
+
+    // Added after the other layers, so requests for the metrics themselves aren't counted
+    let metrics_app = metrics::router()
+        .layer(Extension(auth_client))
+        .layer(Extension(metrics));
+
+    match metrics_addr {
+        Some(metrics_addr) => {
+            tracing::debug!("serving metrics on {}", metrics_addr);
+            tokio::spawn(async move {
+                axum::Server::bind(&metrics_addr)
+                    .serve(metrics_app.into_make_service())
+                    .with_graceful_shutdown(shutdown_signal())
+                    .await
+                    .unwrap();
+            });
+        }
+        None => app = app.merge(metrics_app),
+    }
+
+    if !allowed_origins.is_empty() {
+        app = app.layer(cors::layer(allowed_origins, cors_max_age));
+    }
+
+    // Outermost so the latency covers all other layers, including CORS preflights
+    app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(trace::MakeRequestSpan::new(request_log_level))
+            .on_response(
+                DefaultOnResponse::new()
+                    .level(request_log_level)
+                    .latency_unit(LatencyUnit::Millis),
+            ),
+    );
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
+
+    tracing::info!("shutdown complete");
+}
+
+/// Resolves on SIGINT or SIGTERM so in-flight requests (and writes) can finish before exiting
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutdown signal received, draining in-flight requests");
+}
+
+fn required_env(key: &str) -> String {
+    env::var(key).unwrap_or_else(|_| panic!("env var {key} not set"))
+}
+
+// Accepts 1/true/yes/on and 0/false/no/off (case-insensitive), anything else is a startup error
+fn env_flag(key: &str, default: bool) -> bool {
+    match env::var(key) {
+        Ok(value) => match value.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" | "" => false,
+            _ => panic!("env var {key} is not a boolean: {value}"),
+        },
+        Err(_) => default,
+    }
 }
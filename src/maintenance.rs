@@ -0,0 +1,54 @@
+use axum::{
+    http::{header::RETRY_AFTER, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct MaintenanceWindow {
+    pub message: String,
+    pub retry_after_secs: u64,
+}
+
+/// Read-only mode toggled at runtime, e.g. while backups are running
+#[derive(Clone, Default)]
+pub struct Maintenance(Arc<RwLock<Option<MaintenanceWindow>>>);
+
+impl Maintenance {
+    pub fn set(&self, window: Option<MaintenanceWindow>) {
+        *self.0.write() = window;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.0.read().is_some()
+    }
+}
+
+/// Turns away everything but reads while a maintenance window is active
+pub async fn guard<B>(
+    Extension(maintenance): Extension<Maintenance>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // PROPFIND only lists WebDAV properties
+    let is_read = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) || request.method().as_str() == "PROPFIND";
+
+    if !is_read {
+        if let Some(window) = maintenance.0.read().clone() {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, window.retry_after_secs.to_string())],
+                window.message,
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,253 @@
+use pulldown_cmark::{html, Event, Options, Parser, TagEnd};
+
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+}
+
+/// Renders markdown to HTML that is safe to embed, i.e. without scripts or event handlers
+pub fn to_html(markdown: &str) -> String {
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options()));
+
+    // Task lists are rendered as disabled checkboxes which the default allow-list would strip
+    ammonia::Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+/// Strips all markup, keeping only the text with one line per block
+pub fn to_plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+
+    for event in Parser::new_ext(markdown, options()) {
+        match event {
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) | Event::DisplayMath(t) => {
+                text.push_str(&t)
+            }
+            Event::SoftBreak => text.push(' '),
+            Event::HardBreak
+            | Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::CodeBlock
+                | TagEnd::TableHead
+                | TagEnd::TableRow,
+            ) if !text.is_empty() && !text.ends_with('\n') => text.push('\n'),
+            Event::End(TagEnd::TableCell) => text.push(' '),
+            _ => {}
+        }
+    }
+
+    text.truncate(text.trim_end().len());
+    text
+}
+
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+/// The parts of YAML front matter that are understood, everything else is ignored
+#[derive(Default)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Splits YAML front matter off the start of a document, returning it parsed along with the
+/// body. Documents without (complete) front matter are all body.
+pub fn split_front_matter(contents: &str) -> (FrontMatter, &str) {
+    let Some((header, body)) = front_matter(contents) else {
+        return (FrontMatter::default(), contents);
+    };
+
+    let mut front_matter = FrontMatter::default();
+    let mut lines = header.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if let Some(title) = line.strip_prefix("title:") {
+            front_matter.title = Some(parse_scalar(title)).filter(|title| !title.is_empty());
+        } else if let Some(tags) = line.strip_prefix("tags:") {
+            let tags = tags.trim();
+
+            front_matter.tags = if tags.is_empty() {
+                // Block sequence, one `- tag` per line
+                let mut items = Vec::new();
+                while let Some(item) = lines.peek().and_then(|l| l.trim().strip_prefix('-')) {
+                    items.push(parse_scalar(item));
+                    lines.next();
+                }
+                items
+            } else {
+                // Flow sequence `[a, b]` or just `a, b`
+                tags.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .split(',')
+                    .map(parse_scalar)
+                    .collect()
+            };
+
+            front_matter.tags.retain(|tag| !tag.is_empty());
+        }
+    }
+
+    (front_matter, body)
+}
+
+/// Sets the title in the front matter of a document, keeping everything else in it. An empty
+/// title removes it, along with front matter that would be left empty.
+pub fn set_title(contents: &str, title: &str) -> String {
+    let (header, body) = front_matter(contents).unwrap_or(("", contents));
+
+    let mut lines: Vec<String> = header
+        .lines()
+        .filter(|line| !line.starts_with("title:"))
+        .map(str::to_owned)
+        .collect();
+
+    if !title.is_empty() {
+        // JSON strings are valid double-quoted YAML scalars, so any title survives the round trip
+        let title = serde_json::to_string(title).expect("failed to serialize title");
+        lines.insert(0, format!("title: {title}"));
+    }
+
+    with_front_matter(&lines, body)
+}
+
+/// Sets the tags in the front matter of a document, keeping everything else in it. No tags
+/// remove them, along with front matter that would be left empty.
+pub fn set_tags(contents: &str, tags: &[String]) -> String {
+    let (header, body) = front_matter(contents).unwrap_or(("", contents));
+
+    let mut lines = Vec::new();
+    let mut in_tags = false;
+
+    for line in header.lines() {
+        if let Some(value) = line.strip_prefix("tags:") {
+            // A block sequence follows on the next lines
+            in_tags = value.trim().is_empty();
+        } else if !(in_tags && line.trim().starts_with('-')) {
+            in_tags = false;
+            lines.push(line.to_owned());
+        }
+    }
+
+    if !tags.is_empty() {
+        let tags: Vec<_> = tags
+            .iter()
+            .map(|tag| serde_json::to_string(tag).expect("failed to serialize tag"))
+            .collect();
+        lines.push(format!("tags: [{}]", tags.join(", ")));
+    }
+
+    with_front_matter(&lines, body)
+}
+
+fn with_front_matter(lines: &[String], body: &str) -> String {
+    if lines.is_empty() {
+        return body.to_owned();
+    }
+
+    format!(
+        "{FRONT_MATTER_DELIMITER}\n{}\n{FRONT_MATTER_DELIMITER}\n{body}",
+        lines.join("\n")
+    )
+}
+
+// Raw front matter between the delimiters and the body following it
+fn front_matter(contents: &str) -> Option<(&str, &str)> {
+    let rest = contents
+        .strip_prefix(FRONT_MATTER_DELIMITER)
+        .and_then(|rest| {
+            rest.strip_prefix('\n')
+                .or_else(|| rest.strip_prefix("\r\n"))
+        })?;
+
+    let mut offset = 0;
+
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == FRONT_MATTER_DELIMITER {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+
+        offset += line.len();
+    }
+
+    None
+}
+
+/// First non-empty line as plain text, for documents that don't have a title of their own
+pub fn first_line(markdown: &str) -> Option<String> {
+    markdown
+        .lines()
+        .map(to_plain_text)
+        .find(|line| !line.is_empty())
+}
+
+/// Number of words in a document, tokens made up of markup alone like list bullets don't count
+pub fn word_count(markdown: &str) -> usize {
+    markdown
+        .split_whitespace()
+        .filter(|token| token.chars().any(char::is_alphanumeric))
+        .count()
+}
+
+/// Splits a document into chunks of at most `max_bytes`, only ever between paragraphs. A single
+/// paragraph exceeding the limit is kept whole and makes for an oversized chunk.
+pub fn split_at_paragraphs(contents: &str, max_bytes: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut paragraph = String::new();
+
+    for line in contents.split_inclusive('\n') {
+        paragraph.push_str(line);
+
+        // Blank lines end a paragraph and stay with it
+        if line.trim().is_empty() {
+            push_paragraph(&mut chunks, &mut chunk, &paragraph, max_bytes);
+            paragraph.clear();
+        }
+    }
+
+    push_paragraph(&mut chunks, &mut chunk, &paragraph, max_bytes);
+
+    // Whitespace-only documents still make for one (empty) chunk
+    if !chunk.trim().is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.trim_end_matches(['\r', '\n']).to_owned() + "\n")
+        .collect()
+}
+
+fn push_paragraph(chunks: &mut Vec<String>, chunk: &mut String, paragraph: &str, max_bytes: usize) {
+    // Extra blank lines between paragraphs stay with the previous one
+    if !chunk.trim().is_empty()
+        && !paragraph.trim().is_empty()
+        && chunk.len() + paragraph.trim_end().len() > max_bytes
+    {
+        chunks.push(std::mem::take(chunk));
+    }
+
+    chunk.push_str(paragraph);
+}
+
+fn parse_scalar(value: &str) -> String {
+    let value = value.trim();
+
+    if value.starts_with('"') {
+        serde_json::from_str(value).unwrap_or_else(|_| value.trim_matches('"').to_owned())
+    } else if let Some(quoted) = value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+    {
+        quoted.replace("''", "'")
+    } else {
+        value.to_owned()
+    }
+}
@@ -0,0 +1,258 @@
+use crate::auth::oidc::AuthClient;
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{header::CONTENT_TYPE, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+// Requests that matched no route share one label, otherwise every probed URL would be a series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Counters exposed in the Prometheus text format, shared by everything that records into them
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    // Keyed by route template and status code, sorted so the output is stable
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    introspection_cache_hits: AtomicU64,
+    introspection_cache_misses: AtomicU64,
+    documents_read: AtomicU64,
+    documents_written: AtomicU64,
+
+    // Gauges refreshed by a periodic scan of the storage
+    users: AtomicU64,
+    documents: AtomicU64,
+
+    // Read from the auth client on every scrape
+    pending_login_sessions: AtomicU64,
+}
+
+impl Metrics {
+    pub fn introspection_cache_hit(&self) {
+        self.0
+            .introspection_cache_hits
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn introspection_cache_miss(&self) {
+        self.0
+            .introspection_cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn document_read(&self) {
+        self.0.documents_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn document_written(&self) {
+        self.0.documents_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_storage_totals(&self, users: usize, documents: usize) {
+        self.0.users.store(users as u64, Ordering::Relaxed);
+        self.0.documents.store(documents as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_pending_login_sessions(&self, sessions: usize) {
+        self.0
+            .pending_login_sessions
+            .store(sessions as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "jrnl_http_requests_total",
+            "Requests handled, by route and status",
+            "counter",
+        );
+        for ((route, status), count) in self.0.requests.lock().iter() {
+            let _ = writeln!(
+                output,
+                "jrnl_http_requests_total{{route=\"{}\",status=\"{status}\"}} {count}",
+                escape_label(route)
+            );
+        }
+
+        let counters = [
+            (
+                "jrnl_introspection_cache_hits_total",
+                "Access tokens resolved from the introspection cache",
+                &self.0.introspection_cache_hits,
+            ),
+            (
+                "jrnl_introspection_cache_misses_total",
+                "Access tokens introspected at the identity provider",
+                &self.0.introspection_cache_misses,
+            ),
+            (
+                "jrnl_documents_read_total",
+                "Documents read from storage",
+                &self.0.documents_read,
+            ),
+            (
+                "jrnl_documents_written_total",
+                "Documents created, written or appended to",
+                &self.0.documents_written,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            write_header(&mut output, name, help, "counter");
+            let _ = writeln!(output, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let gauges = [
+            (
+                "jrnl_users_total",
+                "Users with a storage directory, as of the last scan",
+                &self.0.users,
+            ),
+            (
+                "jrnl_documents_total",
+                "Documents of all users, as of the last scan",
+                &self.0.documents,
+            ),
+            (
+                "jrnl_pending_login_sessions",
+                "Logins that were started but neither completed nor expired yet",
+                &self.0.pending_login_sessions,
+            ),
+        ];
+
+        for (name, help, gauge) in gauges {
+            write_header(&mut output, name, help, "gauge");
+            let _ = writeln!(output, "{name} {}", gauge.load(Ordering::Relaxed));
+        }
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts every request by the template of the route it matched and its response status
+pub async fn track_requests<B>(
+    Extension(metrics): Extension<Metrics>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, MatchedPath::as_str)
+        .to_owned();
+
+    let response = next.run(request).await;
+
+    *metrics
+        .0
+        .requests
+        .lock()
+        .entry((route, response.status().as_u16()))
+        .or_default() += 1;
+
+    response
+}
+
+pub fn router() -> Router<(), Body> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn metrics(
+    Extension(metrics): Extension<Metrics>,
+    Extension(auth_client): Extension<AuthClient>,
+) -> impl IntoResponse {
+    metrics.set_pending_login_sessions(auth_client.pending_sessions());
+    ([(CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)], metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        self,
+        tests::{config, document},
+        StorageState, UserStorage,
+    };
+    use crate::{api::tests::body, auth::oidc};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn storage_totals_are_counted_on_refresh() {
+        let config = config();
+        let metrics = Metrics::default();
+        let state = StorageState::new(None, metrics.clone());
+
+        for (user, identifiers) in [("alice", [1, 2].as_slice()), ("bob", &[3])] {
+            let storage = UserStorage::new(config.clone(), state.clone(), user);
+            for identifier in identifiers {
+                storage.write(document(*identifier, "Entry")).await.unwrap();
+            }
+        }
+
+        // Hidden directories like the git repository aren't users
+        tokio::fs::create_dir_all(config.location.join(".git"))
+            .await
+            .unwrap();
+
+        assert!(metrics.render().contains("\njrnl_documents_total 0\n"));
+
+        storage::refresh_totals(&config, &state).await.unwrap();
+        let output = metrics.render();
+        assert!(output.contains("# TYPE jrnl_users_total gauge\njrnl_users_total 2\n"));
+        assert!(output.contains("# TYPE jrnl_documents_total gauge\njrnl_documents_total 3\n"));
+
+        tokio::fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pending_login_sessions_are_read_on_scrape() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let app = router()
+            .layer(Extension(auth_client.clone()))
+            .layer(Extension(Metrics::default()));
+
+        auth_client.create_session(None);
+        auth_client.create_session(None);
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let output = String::from_utf8(body(response).await).unwrap();
+        assert!(output
+            .contains("# TYPE jrnl_pending_login_sessions gauge\njrnl_pending_login_sessions 2\n"));
+    }
+}
@@ -1,106 +1,2077 @@
-use crate::{auth::AuthenticatedUser, ENV_STORAGE_LOCATION};
-use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
-use serde::{Deserialize, Serialize};
-use std::{env, path::PathBuf};
-use tokio::{fs, io};
+use crate::{
+    auth::AuthenticatedUser,
+    git::{Change, GitCommitter},
+    maintenance::Maintenance,
+    markdown,
+    metrics::Metrics,
+};
+use axum::body::Bytes;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+    response::Html,
+};
+use encoding_rs::Encoding;
+use futures::{Stream, StreamExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    io::{Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use time_tz::{OffsetDateTimeExt, Tz};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncRead, AsyncWriteExt},
+    sync::{
+        Mutex, OwnedMutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, OwnedSemaphorePermit,
+        RwLock, Semaphore,
+    },
+};
+use tracing::{info, warn};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
 
-const STORAGE_EXTENSION: &'static str = "md";
-const TRUNCATE_LEN: usize = 1024;
+const STORAGE_EXTENSION: &str = "md";
+pub const TRUNCATE_LEN: usize = 1024;
+
+// Average silent reading speed, used for the reading time estimate
+const WORDS_PER_MINUTE: usize = 200;
+
+// Number of recent sequenced appends to a document that later ones can still be ordered before
+const SEQUENCE_WINDOW: usize = 32;
+
+// Expired documents are hidden immediately, the purge only catches up on deleting them
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Never shows up in listings as its name doesn't parse as an identifier
+const SCRATCH_FILE: &str = ".scratch.md";
+const AVATAR_FILE: &str = ".avatar";
+const FEATURES_FILE: &str = ".features.json";
 
 // Unix timestamp that (almost) uniquely identifies a document
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DocumentIdentifier(u64);
 
+// JavaScript numbers lose precision beyond 2^53, so clients can opt into identifiers as strings.
+// Read by the serializer which has no access to the config, hence set once at startup.
+static IDENTIFIERS_AS_STRINGS: AtomicBool = AtomicBool::new(false);
+
+pub fn serialize_identifiers_as_strings(enabled: bool) {
+    IDENTIFIERS_AS_STRINGS.store(enabled, Ordering::Relaxed);
+}
+
+impl Serialize for DocumentIdentifier {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_as(IDENTIFIERS_AS_STRINGS.load(Ordering::Relaxed), serializer)
+    }
+}
+
+// Both representations are accepted regardless of the configured output
+impl<'de> Deserialize<'de> for DocumentIdentifier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IdentifierVisitor;
+
+        impl de::Visitor<'_> for IdentifierVisitor {
+            type Value = DocumentIdentifier;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a document identifier as number or string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(DocumentIdentifier(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                value.parse().map(DocumentIdentifier).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(IdentifierVisitor)
+    }
+}
+
+impl DocumentIdentifier {
+    // Separate from the global switch so both representations can be produced explicitly
+    fn serialize_as<S: Serializer>(self, string: bool, serializer: S) -> Result<S::Ok, S::Error> {
+        if string {
+            serializer.collect_str(&self.0)
+        } else {
+            serializer.serialize_u64(self.0)
+        }
+    }
+
+    /// Name of the file storing the document
+    pub fn file_name(self) -> String {
+        format!("{}.{STORAGE_EXTENSION}", self.0)
+    }
+
+    /// Inverse of [`Self::file_name`], only accepting canonical names since `+1.md` or `01.md`
+    /// would map to another document's file
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let identifier = name
+            .strip_suffix(STORAGE_EXTENSION)?
+            .strip_suffix('.')?
+            .parse()
+            .ok()
+            .map(DocumentIdentifier)?;
+
+        (identifier.file_name() == name).then_some(identifier)
+    }
+
+    /// Creation time in the given timezone, the identifier being a unix timestamp in milliseconds
+    pub fn local_datetime(self, timezone: &Tz) -> Option<OffsetDateTime> {
+        OffsetDateTime::from_unix_timestamp_nanos(self.0 as i128 * 1_000_000)
+            .ok()
+            .map(|datetime| datetime.to_timezone(timezone))
+    }
+}
+
+impl fmt::Display for DocumentIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Document {
     pub identifier: DocumentIdentifier,
     pub contents: String,
+
+    // Stored as front matter, which is not part of `contents`. Reads fall back to the first line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    // Hash of the complete document as served by a read, even if `contents` is truncated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    // Local creation date (YYYY-MM-DD) and ISO weekday (Monday = 1) in the configured timezone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weekday: Option<u8>,
+
+    // RFC 3339 timestamps in the configured timezone, creation is derived from the identifier
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+
+    // Counted over the whole document even if `contents` is truncated, without front matter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub word_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reading_minutes: Option<usize>,
+
+    // Informational only, set if a large document threshold is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_warning: Option<bool>,
+}
+
+impl Document {
+    pub fn new(identifier: DocumentIdentifier, contents: String) -> Self {
+        Self {
+            identifier,
+            contents,
+            title: None,
+            tags: Vec::new(),
+            sha256: None,
+            date: None,
+            weekday: None,
+            created_at: None,
+            modified_at: None,
+            word_count: None,
+            reading_minutes: None,
+            size_warning: None,
+        }
+    }
+}
+
+pub struct DocumentMetadata {
+    pub identifier: DocumentIdentifier,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+pub enum DecodedContents {
+    Text {
+        contents: String,
+        // Set when the file was not valid UTF-8 and had to be transcoded
+        source_encoding: Option<&'static Encoding>,
+    },
+    Binary(Vec<u8>),
+}
+
+impl DecodedContents {
+    pub fn sha256(&self) -> String {
+        match self {
+            Self::Text { contents, .. } => sha256_hex(contents.as_bytes()),
+            Self::Binary(bytes) => sha256_hex(bytes),
+        }
+    }
+
+    // In bytes, after transcoding for text
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Text { contents, .. } => contents.len(),
+            Self::Binary(bytes) => bytes.len(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub location: PathBuf,
+
+    // Used for files that are not valid UTF-8, e.g. legacy imports in latin-1
+    pub fallback_encoding: Option<&'static Encoding>,
+
+    pub trim_trailing_whitespace: bool,
+    pub ensure_final_newline: bool,
+
+    // Writes with a larger share of control characters are rejected, if set
+    pub reject_binary_threshold: Option<f64>,
+
+    // Writes with larger contents (in UTF-8 bytes) are rejected
+    pub max_document_bytes: usize,
+
+    // Documents declaring more tags in their front matter are rejected or cut down, keeping
+    // tag spam out of the tag aggregation
+    pub max_tags_per_document: usize,
+    pub tag_limit_mode: TagLimitMode,
+
+    // Created documents larger than this are split at paragraphs into several ones, if set
+    pub autosplit_bytes: Option<usize>,
+
+    // Documents larger than this are flagged so clients can suggest splitting them, if set
+    pub large_document_bytes: Option<usize>,
+
+    // Upper bound for the number of documents returned by a single listing
+    pub max_listing: usize,
+
+    // Upper bound for previews requested by clients
+    pub max_preview_len: usize,
+
+    // Number of documents read in parallel when aggregating over all of them
+    pub read_concurrency: usize,
+
+    // Used whenever an identifier is turned into a calendar date
+    pub timezone: &'static Tz,
+
+    // Requests beyond this many in flight for the same user are turned away, if set
+    pub max_concurrent_per_user: Option<usize>,
+
+    // Directory of named entry templates (`<name>.md`) shared by all users
+    pub templates_dir: Option<PathBuf>,
+
+    pub fsync: FsyncPolicy,
+
+    // Features only available to users who have them enabled individually
+    pub gated_features: Vec<String>,
+
+    // Seeded as the first document of users who don't have a storage directory yet
+    pub welcome_entry: Option<Arc<str>>,
+
+    // Second directory that changed files are copied to after the primary write succeeded.
+    // Best-effort only: failures are logged, never reported, so it is no transactional replica.
+    pub mirror: Option<PathBuf>,
+
+    // Documents created longer ago are hidden and eventually deleted for good, if set
+    pub max_entry_age: Option<Duration>,
+}
+
+impl StorageConfig {
+    pub fn size_warning(&self, size: usize) -> Option<bool> {
+        self.large_document_bytes.map(|threshold| size > threshold)
+    }
+
+    /// Checks applied to every document before it is written, whichever way it arrives
+    pub fn validate(&self, contents: &str) -> io::Result<()> {
+        if contents.len() > self.max_document_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "document exceeds the size limit",
+            ));
+        }
+
+        if self.tag_limit_mode == TagLimitMode::Reject
+            && markdown::split_front_matter(contents).0.tags.len() > self.max_tags_per_document
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "document declares too many tags",
+            ));
+        }
+
+        if let Some(threshold) = self.reject_binary_threshold {
+            if looks_binary(contents, threshold) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "document looks like binary data",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_expired(&self, identifier: DocumentIdentifier) -> bool {
+        let Some(max_age) = self.max_entry_age else {
+            return false;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        now.saturating_sub(Duration::from_millis(identifier.0)) > max_age
+    }
+}
+
+/// Partial update of a document, e.g. `{"append": "text"}`
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchOperation {
+    Append(String),
+    Prepend(String),
+}
+
+impl PatchOperation {
+    pub fn text(&self) -> &str {
+        match self {
+            Self::Append(text) | Self::Prepend(text) => text,
+        }
+    }
+}
+
+/// What happens to writes declaring more tags than allowed
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum TagLimitMode {
+    #[default]
+    Reject,
+    // Keeps the first tags up to the limit
+    Truncate,
+}
+
+impl FromStr for TagLimitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" | "" => Ok(Self::Reject),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(format!("unknown tag limit mode {s}")),
+        }
+    }
+}
+
+/// How hard writes try to make sure data reached the disk before reporting success
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // Leaves flushing to the OS, fast but recent writes may be lost on power failure
+    #[default]
+    None,
+    // Syncs file contents
+    Data,
+    // Syncs file contents, metadata and the directory entry of new files
+    Full,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "" => Ok(Self::None),
+            "data" => Ok(Self::Data),
+            "full" => Ok(Self::Full),
+            _ => Err(format!("unknown fsync policy {s}")),
+        }
+    }
+}
+
+/// Runtime state shared by all requests
+#[derive(Clone, Default)]
+pub struct StorageState {
+    // Last identifier allocated per user directory, locked while allocating the next one
+    allocated_ids: Arc<parking_lot::Mutex<HashMap<PathBuf, Arc<Mutex<u64>>>>>,
+
+    // Limits the requests in flight per user so one misbehaving client can't starve the others
+    in_flight: Arc<parking_lot::Mutex<HashMap<String, Arc<Semaphore>>>>,
+
+    // Serializes modifications of the same document, so read-modify-write updates aren't lost
+    document_locks: Arc<parking_lot::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>>,
+
+    // Most recent sequenced appends per document, so later ones can be slotted in between
+    sequenced_appends: Arc<parking_lot::Mutex<HashMap<PathBuf, SequencedTail>>>,
+
+    // Shared by every modification of a user's files, exclusive while exporting them for deletion
+    user_locks: Arc<parking_lot::Mutex<HashMap<PathBuf, Arc<RwLock<()>>>>>,
+
+    // Set when the storage root is versioned with git
+    git: Option<GitCommitter>,
+
+    metrics: Metrics,
+}
+
+// Sequence numbers and lengths of the appends a document ends with, oldest first. Only the
+// lengths are kept, the hash tells whether the document still ends with the same texts.
+struct SequencedTail {
+    appends: Vec<(u64, usize)>,
+    sha256: String,
+}
+
+impl SequencedTail {
+    fn split<'a>(&self, contents: &'a str) -> Option<(&'a str, Vec<(u64, &'a str)>)> {
+        let len: usize = self.appends.iter().map(|(_, len)| len).sum();
+        let base_len = contents.len().checked_sub(len)?;
+        let (base, mut tail) = (contents.get(..base_len)?, contents.get(base_len..)?);
+
+        if sha256_hex(tail.as_bytes()) != self.sha256 {
+            return None;
+        }
+
+        let mut appends = Vec::with_capacity(self.appends.len());
+        for (sequence, len) in &self.appends {
+            let (text, rest) = (tail.get(..*len)?, tail.get(*len..)?);
+            appends.push((*sequence, text));
+            tail = rest;
+        }
+
+        Some((base, appends))
+    }
+}
+
+// Must not be acquired twice by the same task, a waiting exclusive lock would deadlock it
+struct DocumentLock {
+    _user: OwnedRwLockReadGuard<()>,
+    _document: OwnedMutexGuard<()>,
+}
+
+impl StorageState {
+    pub fn new(git: Option<GitCommitter>, metrics: Metrics) -> Self {
+        Self {
+            git,
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    fn allocated_id(&self, path: &Path) -> Arc<Mutex<u64>> {
+        self.allocated_ids
+            .lock()
+            .entry(path.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    async fn lock_document(&self, path: &Path) -> DocumentLock {
+        let user = self
+            .lock_user(
+                path.parent()
+                    .expect("documents are within a user directory"),
+            )
+            .await;
+
+        let lock = {
+            let mut locks = self.document_locks.lock();
+
+            // Guards keep their lock alive, so a lock without other references is unused
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(path.to_owned()).or_default().clone()
+        };
+
+        DocumentLock {
+            _user: user,
+            _document: lock.lock_owned().await,
+        }
+    }
+
+    fn user_lock(&self, path: &Path) -> Arc<RwLock<()>> {
+        let mut locks = self.user_locks.lock();
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(path.to_owned()).or_default().clone()
+    }
+
+    async fn lock_user(&self, path: &Path) -> OwnedRwLockReadGuard<()> {
+        self.user_lock(path).read_owned().await
+    }
+
+    fn try_acquire_request(&self, user_id: &str, limit: usize) -> Option<OwnedSemaphorePermit> {
+        let mut in_flight = self.in_flight.lock();
+
+        // Permits keep their semaphore alive, so a semaphore without other references is idle
+        in_flight.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+
+        in_flight
+            .entry(user_id.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone()
+            .try_acquire_owned()
+            .ok()
+    }
 }
 
 pub struct UserStorage {
+    user_id: String,
     path: PathBuf,
+    config: StorageConfig,
+    state: StorageState,
+
+    // Held for as long as the request is being handled
+    _request_permit: Option<OwnedSemaphorePermit>,
 }
 
 impl UserStorage {
-    pub fn new(user_id: impl AsRef<str>) -> Self {
-        let root: PathBuf = env::var(ENV_STORAGE_LOCATION)
-            .expect(&format!("env var {ENV_STORAGE_LOCATION} not set"))
-            .into();
-
+    pub fn new(config: StorageConfig, state: StorageState, user_id: impl AsRef<str>) -> Self {
         Self {
-            path: root.join(user_id.as_ref()),
+            user_id: user_id.as_ref().to_owned(),
+            path: config.location.join(user_id.as_ref()),
+            config,
+            state,
+            _request_permit: None,
         }
     }
 
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Reads a document, optionally truncated to at most `truncate` bytes
     pub async fn read(
         &self,
         identifier: DocumentIdentifier,
-        truncate: bool,
+        truncate: Option<usize>,
     ) -> io::Result<Document> {
-        let mut contents = fs::read_to_string(self.doc_path(identifier)).await?;
+        let decoded = self.read_decoded(identifier).await?;
+        let sha256 = decoded.sha256();
+        let size_warning = self.config.size_warning(decoded.size());
+        let contents = match decoded {
+            DecodedContents::Text { contents, .. } => contents,
+            DecodedContents::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        };
 
-        if truncate {
-            contents.truncate(TRUNCATE_LEN);
+        let (front_matter, body) = markdown::split_front_matter(&contents);
+        let title = front_matter.title.or_else(|| markdown::first_line(body));
+        let word_count = markdown::word_count(body);
+        let mut contents = body.to_owned();
+
+        if let Some(len) = truncate {
+            truncate_at_char_boundary(&mut contents, len);
         }
 
+        let local = identifier.local_datetime(self.config.timezone);
+
         Ok(Document {
-            identifier,
-            contents,
+            sha256: Some(sha256),
+            date: local.map(|datetime| datetime.date().to_string()),
+            weekday: local.map(|datetime| datetime.weekday().number_from_monday()),
+            created_at: local.and_then(|datetime| datetime.format(&Rfc3339).ok()),
+            modified_at: self.modified_at(identifier).await,
+            title,
+            tags: front_matter.tags,
+            word_count: Some(word_count),
+            reading_minutes: Some(word_count.div_ceil(WORDS_PER_MINUTE)),
+            size_warning,
+            ..Document::new(identifier, contents)
         })
     }
 
-    pub async fn write(&self, document: Document) -> io::Result<()> {
-        let doc_path = self.doc_path(document.identifier);
+    /// Last modification time of a document, `None` where the filesystem does not track it
+    pub async fn modified_at(&self, identifier: DocumentIdentifier) -> Option<String> {
+        let modified = fs::metadata(self.doc_path(identifier).ok()?)
+            .await
+            .ok()?
+            .modified()
+            .ok()?;
 
-        fs::create_dir_all(&self.path).await?;
-        fs::write(doc_path, document.contents).await
+        OffsetDateTime::from(modified)
+            .to_timezone(self.config.timezone)
+            .format(&Rfc3339)
+            .ok()
     }
 
-    pub async fn entries(&self) -> io::Result<Vec<Document>> {
-        fs::create_dir_all(&self.path).await?;
+    pub async fn read_decoded(
+        &self,
+        identifier: DocumentIdentifier,
+    ) -> io::Result<DecodedContents> {
+        self.check_expiry(identifier)?;
 
-        let mut entries = fs::read_dir(&self.path).await?;
-        let mut documents = Vec::new();
+        let contents = self.read_file(self.doc_path(identifier)?).await?;
+        self.state.metrics.document_read();
+        Ok(contents)
+    }
 
-        while let Some(entry) = entries.next_entry().await? {
-            if let Some((name, extension)) = entry
-                .file_name()
-                .to_str()
-                .map(|n| n.rsplit_once('.'))
-                .flatten()
+    pub async fn read_scratch(&self) -> io::Result<DecodedContents> {
+        self.read_file(self.path.join(SCRATCH_FILE)).await
+    }
+
+    async fn read_file(&self, path: PathBuf) -> io::Result<DecodedContents> {
+        let bytes = fs::read(path).await?;
+
+        let bytes = match String::from_utf8(bytes) {
+            Ok(contents) => {
+                return Ok(DecodedContents::Text {
+                    contents,
+                    source_encoding: None,
+                })
+            }
+            Err(err) => err.into_bytes(),
+        };
+
+        if let Some(encoding) = self.config.fallback_encoding {
+            if let Some(contents) =
+                encoding.decode_without_bom_handling_and_without_replacement(&bytes)
             {
-                if extension != STORAGE_EXTENSION {
-                    continue;
-                }
+                return Ok(DecodedContents::Text {
+                    contents: contents.into_owned(),
+                    source_encoding: Some(encoding),
+                });
+            }
+        }
 
-                if let Ok(identifier) = name.parse().map(DocumentIdentifier) {
-                    let document = self.read(identifier, true).await?;
-                    documents.push(document);
-                }
+        Ok(DecodedContents::Binary(bytes))
+    }
+
+    /// Appends a stream of chunks to a document. Multibyte characters split across chunk
+    /// boundaries are reassembled, and the document limit is enforced while reading. Memory
+    /// therefore never exceeds the limit, however long the stream runs. The result is written
+    /// like any other document, so either the whole stream is appended or nothing is.
+    pub async fn append_stream(
+        &self,
+        identifier: DocumentIdentifier,
+        mut chunks: impl Stream<Item = io::Result<Bytes>> + Unpin,
+    ) -> io::Result<()> {
+        self.check_expiry(identifier)?;
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+
+        let mut contents = match self.read_file(path.clone()).await {
+            Ok(DecodedContents::Text { contents, .. }) => contents,
+            Ok(DecodedContents::Binary(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "binary documents can't be appended to",
+                ))
             }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut pending = Vec::new();
+
+        while let Some(chunk) = chunks.next().await {
+            pending.extend_from_slice(&chunk?);
+
+            if contents.len() + pending.len() > self.config.max_document_bytes {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "appended document exceeds the size limit",
+                ));
+            }
+
+            let complete = match std::str::from_utf8(&pending) {
+                Ok(text) => text.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+
+            contents.push_str(std::str::from_utf8(&pending[..complete]).expect("validated above"));
+            pending.drain(..complete);
         }
 
-        documents.sort_unstable_by_key(|d| d.identifier);
-        documents.reverse();
+        if !pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream ended within a multibyte character",
+            ));
+        }
 
-        Ok(documents)
+        self.config.validate(&contents)?;
+        self.write_file(path, contents).await?;
+
+        self.record_change("append", identifier);
+        self.state.metrics.document_written();
+        Ok(())
     }
 
-    fn doc_path(&self, document: DocumentIdentifier) -> PathBuf {
-        self.path
-            .join(format!("{}.{STORAGE_EXTENSION}", document.0))
+    pub async fn exists(&self, identifier: DocumentIdentifier) -> io::Result<bool> {
+        if self.config.is_expired(identifier) {
+            return Ok(false);
+        }
+
+        fs::try_exists(self.doc_path(identifier)?).await
     }
-}
 
-#[async_trait]
-impl<S> FromRequestParts<S> for UserStorage
-where
-    S: Send + Sync,
-{
-    type Rejection = <AuthenticatedUser as FromRequestParts<S>>::Rejection;
+    // Expired documents behave as if they were purged already, which may not have happened yet
+    fn check_expiry(&self, identifier: DocumentIdentifier) -> io::Result<()> {
+        if self.config.is_expired(identifier) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "document exceeded the maximum age",
+            ));
+        }
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let user = AuthenticatedUser::from_request_parts(parts, state).await?;
-        Ok(UserStorage::new(user.subject))
+        Ok(())
+    }
+
+    /// Writes a document, replacing its front matter if it comes with a title
+    pub async fn write(&self, document: Document) -> io::Result<()> {
+        let identifier = document.identifier;
+        let contents = self.checked_contents(document)?;
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+        self.write_file(path, contents).await?;
+
+        self.record_change("write", identifier);
+        self.state.metrics.document_written();
+        Ok(())
+    }
+
+    /// Like [`Self::write`], but fails with `AlreadyExists` instead of replacing a document. The
+    /// file is created exclusively, so a document created concurrently is never overwritten.
+    pub async fn write_new(&self, document: Document) -> io::Result<()> {
+        let identifier = document.identifier;
+        let contents = self.normalize(self.checked_contents(document)?);
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+        fs::create_dir_all(&self.path).await?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+
+        let result = async {
+            file.write_all(contents.as_bytes()).await?;
+            self.sync(&file).await
+        }
+        .await;
+
+        // Nobody else can have written to the file, it didn't exist before
+        if let Err(e) = result {
+            let _ = fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        self.sync_dir().await?;
+        self.mirror(&path).await;
+
+        self.record_change("write", identifier);
+        self.state.metrics.document_written();
+        Ok(())
+    }
+
+    // Contents as they would be written, if the document may be written at all
+    fn checked_contents(&self, document: Document) -> io::Result<String> {
+        let contents = match &document.title {
+            Some(title) => markdown::set_title(&document.contents, title),
+            None => document.contents,
+        };
+
+        self.config.validate(&contents)?;
+        self.check_expiry(document.identifier)?;
+
+        Ok(contents)
+    }
+
+    /// Permanently deletes a single document
+    pub async fn delete(&self, identifier: DocumentIdentifier) -> io::Result<()> {
+        self.check_expiry(identifier)?;
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+        fs::remove_file(&path).await?;
+
+        self.sync_dir().await?;
+        self.unmirror(&path).await;
+
+        self.record_change("delete", identifier);
+        Ok(())
+    }
+
+    /// Applies a partial update to an existing document, atomically as far as other writes
+    /// through this server are concerned
+    pub async fn patch(
+        &self,
+        identifier: DocumentIdentifier,
+        operation: PatchOperation,
+    ) -> io::Result<()> {
+        self.check_expiry(identifier)?;
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+
+        let contents = self.read_patchable(&path).await?;
+
+        let contents = match operation {
+            PatchOperation::Append(text) => contents + &text,
+            // Front matter has to stay at the very top
+            PatchOperation::Prepend(text) => {
+                let (_, body) = markdown::split_front_matter(&contents);
+                let front_matter = &contents[..contents.len() - body.len()];
+                format!("{front_matter}{text}{body}")
+            }
+        };
+
+        self.config.validate(&contents)?;
+        self.write_file(path, contents).await?;
+
+        self.record_change("patch", identifier);
+        self.state.metrics.document_written();
+        Ok(())
+    }
+
+    /// Appends to an existing document such that appends carrying sequence numbers end up in
+    /// sequence order, regardless of the order they arrive in. This holds for the last
+    /// [`SEQUENCE_WINDOW`] sequenced appends to a document through this server, as long as the
+    /// document isn't modified otherwise in between. Beyond that the text is simply appended.
+    pub async fn append_in_sequence(
+        &self,
+        identifier: DocumentIdentifier,
+        text: String,
+        sequence: u64,
+    ) -> io::Result<()> {
+        self.check_expiry(identifier)?;
+
+        let path = self.doc_path(identifier)?;
+        let _lock = self.state.lock_document(&path).await;
+        let contents = self.read_patchable(&path).await?;
+
+        // Only still valid if the document ends exactly with the appends as they were written
+        let tracked = self.state.sequenced_appends.lock().remove(&path);
+        let (base, mut appends) = tracked
+            .and_then(|tail| tail.split(&contents))
+            .unwrap_or((&contents, Vec::new()));
+
+        let position = appends.partition_point(|(s, _)| *s <= sequence);
+        appends.insert(position, (sequence, &text));
+
+        let tail: String = appends.iter().map(|(_, text)| *text).collect();
+        let contents = format!("{base}{tail}");
+
+        self.config.validate(&contents)?;
+        self.write_file(path.clone(), contents).await?;
+
+        // The oldest appends become part of the base once the window is full
+        let excess = appends.len().saturating_sub(SEQUENCE_WINDOW);
+        let appends = &appends[excess..];
+        let tail: String = appends.iter().map(|(_, text)| *text).collect();
+        self.state.sequenced_appends.lock().insert(
+            path,
+            SequencedTail {
+                appends: appends.iter().map(|(s, text)| (*s, text.len())).collect(),
+                sha256: sha256_hex(tail.as_bytes()),
+            },
+        );
+
+        self.record_change("patch", identifier);
+        self.state.metrics.document_written();
+        Ok(())
+    }
+
+    async fn read_patchable(&self, path: &Path) -> io::Result<String> {
+        match self.read_file(path.to_owned()).await? {
+            DecodedContents::Text { contents, .. } => Ok(contents),
+            DecodedContents::Binary(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "binary documents can't be patched",
+            )),
+        }
+    }
+
+    pub async fn write_scratch(&self, contents: String) -> io::Result<()> {
+        let _lock = self.state.lock_user(&self.path).await;
+        self.write_file(self.path.join(SCRATCH_FILE), contents)
+            .await?;
+
+        self.record_change("write", "scratch");
+        Ok(())
+    }
+
+    pub async fn read_avatar(&self) -> io::Result<Vec<u8>> {
+        fs::read(self.path.join(AVATAR_FILE)).await
+    }
+
+    pub async fn write_avatar(&self, image: &[u8]) -> io::Result<()> {
+        let _lock = self.state.lock_user(&self.path).await;
+        self.write_bytes(self.path.join(AVATAR_FILE), image).await?;
+
+        self.record_change("write", "avatar");
+        Ok(())
+    }
+
+    /// Feature flags enabled for the user
+    pub async fn features(&self) -> io::Result<BTreeSet<String>> {
+        match fs::read(self.path.join(FEATURES_FILE)).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set_features(&self, features: &BTreeSet<String>) -> io::Result<()> {
+        let json = serde_json::to_vec(features).map_err(io::Error::other)?;
+        let _lock = self.state.lock_user(&self.path).await;
+        self.write_bytes(self.path.join(FEATURES_FILE), json.as_slice())
+            .await?;
+
+        self.record_change("write", "features");
+        Ok(())
+    }
+
+    /// Ungated features are available to everyone, gated ones only with the user's flag
+    pub async fn has_feature(&self, feature: &str) -> io::Result<bool> {
+        if !self
+            .config
+            .gated_features
+            .iter()
+            .any(|gated| gated == feature)
+        {
+            return Ok(true);
+        }
+
+        Ok(self.features().await?.contains(feature))
+    }
+
+    /// Packs everything stored for the user, including sidecar files, into a zip archive
+    /// spooled like the one of [`Self::archive_documents`]
+    pub async fn archive(&self) -> io::Result<fs::File> {
+        let root = self.path.clone();
+
+        // Otherwise a missing user would be exported as an empty archive
+        fs::metadata(&root).await?;
+
+        spool_archive(move |archive| add_to_archive(archive, &root, &root)).await
+    }
+
+    /// Blocks all modifications of the user's files until the returned guard is dropped
+    pub async fn lock_exclusive(&self) -> OwnedRwLockWriteGuard<()> {
+        self.state.user_lock(&self.path).write_owned().await
+    }
+
+    /// Packs all documents as `<identifier>.md` into a zip archive. The archive is spooled to
+    /// an already unlinked temporary file, so large journals are never held in memory and
+    /// nothing is left behind when the download is aborted.
+    pub async fn archive_documents(&self) -> io::Result<fs::File> {
+        let documents: Vec<_> = self
+            .list()
+            .await?
+            .into_iter()
+            .map(|m| Ok((m.identifier, self.doc_path(m.identifier)?)))
+            .collect::<io::Result<_>>()?;
+
+        spool_archive(move |archive| {
+            let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+            for (identifier, path) in documents {
+                // Documents deleted since they were listed are skipped
+                let mut document = match std::fs::File::open(&path) {
+                    Ok(document) => document,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+
+                archive.start_file(identifier.file_name(), options)?;
+                std::io::copy(&mut document, archive)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the user directory with everything in it
+    pub async fn delete_all(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.path).await?;
+
+        if let Some(mirror) = self.mirror_path(&self.path) {
+            if let Err(e) = fs::remove_dir_all(&mirror).await {
+                warn!("Failed to remove mirror {}: {e}", mirror.display());
+            }
+        }
+
+        self.record_change("delete", "all documents");
+        Ok(())
+    }
+
+    /// Creates the storage directory of a new user, returns false if it already existed
+    async fn initialize(&self) -> io::Result<bool> {
+        fs::create_dir_all(&self.config.location).await?;
+
+        // Only one request can create the directory, so only that one seeds it
+        match fs::create_dir(&self.path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn seed(&self, welcome_entry: &str) -> io::Result<()> {
+        if self.initialize().await? {
+            self.create(welcome_entry.to_owned()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores a new document under a fresh identifier which is never reused, even when
+    /// multiple documents are created within the same millisecond
+    pub async fn create(&self, contents: String) -> io::Result<DocumentIdentifier> {
+        self.config.validate(&contents)?;
+        let contents = self.normalize(contents);
+
+        let _lock = self.state.lock_user(&self.path).await;
+        let allocated_id = self.state.allocated_id(&self.path);
+        let mut last_id = allocated_id.lock().await;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut identifier = DocumentIdentifier(now.max(*last_id + 1));
+
+        fs::create_dir_all(&self.path).await?;
+
+        loop {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.doc_path(identifier)?)
+                .await;
+
+            match file {
+                Ok(mut file) => {
+                    file.write_all(contents.as_bytes()).await?;
+                    self.sync(&file).await?;
+                    break;
+                }
+                // Only happens with clients picking identifiers themselves
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => identifier.0 += 1,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.sync_dir().await?;
+        self.mirror(&self.doc_path(identifier)?).await;
+
+        *last_id = identifier.0;
+        self.record_change("create", identifier);
+        self.state.metrics.document_written();
+
+        Ok(identifier)
+    }
+
+    fn record_change(&self, action: &'static str, target: impl ToString) {
+        if let Some(git) = &self.state.git {
+            git.record(Change {
+                subject: self.user_id.clone(),
+                action,
+                target: target.to_string(),
+            });
+        }
+    }
+
+    async fn write_file(&self, path: PathBuf, contents: String) -> io::Result<()> {
+        let contents = self.normalize(contents);
+        self.write_bytes(path, contents.as_bytes()).await
+    }
+
+    // Writes to a temporary file first and renames it over the target, so a crash or failing
+    // source mid-write leaves either the old or the new version but never a truncated document
+    async fn write_bytes(
+        &self,
+        path: PathBuf,
+        mut contents: impl AsyncRead + Unpin,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.path).await?;
+
+        // Random so concurrent writes to the same file don't share a temporary file
+        let mut temp_name = path.clone().into_os_string();
+        temp_name.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+        let temp_path = PathBuf::from(temp_name);
+
+        let result = async {
+            let mut file = fs::File::create(&temp_path).await?;
+            io::copy(&mut contents, &mut file).await?;
+            self.sync(&file).await?;
+            fs::rename(&temp_path, &path).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+
+        self.sync_dir().await?;
+        self.mirror(&path).await;
+
+        Ok(())
+    }
+
+    // Copies the primary file as a whole, so appends and rewrites are mirrored alike
+    async fn mirror(&self, path: &Path) {
+        let Some(mirror) = self.mirror_path(path) else {
+            return;
+        };
+
+        let result = async {
+            if let Some(parent) = mirror.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            fs::copy(path, &mirror).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            warn!("Failed to mirror {}: {e}", path.display());
+        }
+    }
+
+    async fn unmirror(&self, path: &Path) {
+        let Some(mirror) = self.mirror_path(path) else {
+            return;
+        };
+
+        if let Err(e) = fs::remove_file(&mirror).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove mirror {}: {e}", mirror.display());
+            }
+        }
+    }
+
+    fn mirror_path(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.config.location).ok()?;
+        Some(self.config.mirror.as_ref()?.join(relative))
+    }
+
+    async fn sync(&self, file: &fs::File) -> io::Result<()> {
+        match self.config.fsync {
+            FsyncPolicy::None => Ok(()),
+            FsyncPolicy::Data => file.sync_data().await,
+            FsyncPolicy::Full => file.sync_all().await,
+        }
+    }
+
+    // Newly created files only survive a crash once their directory entry has been synced too
+    async fn sync_dir(&self) -> io::Result<()> {
+        if self.config.fsync == FsyncPolicy::Full {
+            fs::File::open(&self.path).await?.sync_all().await?;
+        }
+
+        Ok(())
+    }
+
+    fn normalize(&self, mut contents: String) -> String {
+        if self.config.tag_limit_mode == TagLimitMode::Truncate {
+            let (front_matter, _) = markdown::split_front_matter(&contents);
+            let max_tags = self.config.max_tags_per_document;
+
+            if front_matter.tags.len() > max_tags {
+                contents = markdown::set_tags(&contents, &front_matter.tags[..max_tags]);
+            }
+        }
+
+        if self.config.trim_trailing_whitespace {
+            contents = trim_trailing_whitespace(&contents);
+        }
+
+        if self.config.ensure_final_newline && !contents.is_empty() {
+            contents.truncate(contents.trim_end_matches(['\r', '\n']).len());
+            contents.push('\n');
+        }
+
+        contents
+    }
+
+    /// Lists all documents, newest first, without reading their contents
+    pub async fn list(&self) -> io::Result<Vec<DocumentMetadata>> {
+        let mut listing = self.list_all().await?;
+        listing.retain(|m| !self.config.is_expired(m.identifier));
+        Ok(listing)
+    }
+
+    // Includes expired documents that have not been purged yet
+    async fn list_all(&self) -> io::Result<Vec<DocumentMetadata>> {
+        // Listing must not create the directory, that would skip seeding the welcome entry
+        let mut entries = match fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut listing = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let identifier = entry
+                .file_name()
+                .to_str()
+                .and_then(DocumentIdentifier::from_file_name);
+
+            if let Some(identifier) = identifier {
+                let metadata = entry.metadata().await?;
+
+                listing.push(DocumentMetadata {
+                    identifier,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+
+        listing.sort_unstable_by_key(|m| m.identifier);
+        listing.reverse();
+
+        Ok(listing)
+    }
+
+    /// Permanently deletes all documents exceeding the maximum age, returns how many
+    pub async fn purge_expired(&self) -> io::Result<usize> {
+        let mut purged = 0;
+
+        for metadata in self.list_all().await? {
+            if !self.config.is_expired(metadata.identifier) {
+                continue;
+            }
+
+            let path = self.doc_path(metadata.identifier)?;
+            let _lock = self.state.lock_document(&path).await;
+
+            match fs::remove_file(&path).await {
+                Ok(()) => purged += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+
+            self.unmirror(&path).await;
+            self.record_change("purge", metadata.identifier);
+        }
+
+        Ok(purged)
+    }
+
+    /// Reads the given documents, preserving their order and truncating them to `preview_len` bytes
+    pub async fn entries(
+        &self,
+        listing: &[DocumentMetadata],
+        preview_len: Option<usize>,
+    ) -> io::Result<Vec<Document>> {
+        let mut documents = Vec::with_capacity(listing.len());
+
+        for metadata in listing {
+            documents.push(self.read(metadata.identifier, preview_len).await?);
+        }
+
+        Ok(documents)
+    }
+
+    /// Path of a document, which is guaranteed to be a direct child of the user directory so
+    /// that no identifier can ever reach files outside of it
+    pub fn doc_path(&self, document: DocumentIdentifier) -> io::Result<PathBuf> {
+        let name = document.file_name();
+        let mut components = Path::new(&name).components();
+
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.path.join(name)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("document identifier {document} does not name a file"),
+            )),
+        }
+    }
+}
+
+// Builds the archive in an already unlinked temporary file, rewound for reading
+async fn spool_archive(
+    build: impl FnOnce(&mut ZipWriter<std::fs::File>) -> io::Result<()> + Send + 'static,
+) -> io::Result<fs::File> {
+    let spool =
+        std::env::temp_dir().join(format!("jrnl-export-{:016x}.zip", rand::random::<u64>()));
+
+    let file = tokio::task::spawn_blocking(move || -> io::Result<std::fs::File> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&spool)?;
+        std::fs::remove_file(&spool)?;
+
+        let mut archive = ZipWriter::new(file);
+        build(&mut archive)?;
+
+        let mut file = archive.finish()?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    Ok(fs::File::from_std(file))
+}
+
+fn add_to_archive(
+    archive: &mut ZipWriter<std::fs::File>,
+    root: &Path,
+    dir: &Path,
+) -> io::Result<()> {
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            add_to_archive(archive, root, &path)?;
+            continue;
+        }
+
+        let name = path
+            .strip_prefix(root)
+            .expect("archived files are within the root")
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        archive.start_file(name, options)?;
+        std::io::copy(&mut std::fs::File::open(&path)?, archive)?;
+    }
+
+    Ok(())
+}
+
+/// Periodically purges expired documents of all users, hard-deleting them from the storage and
+/// mirror. Does nothing unless a maximum entry age is configured, and pauses during maintenance.
+pub async fn purge_expired_documents(
+    config: StorageConfig,
+    state: StorageState,
+    maintenance: Maintenance,
+) {
+    if config.max_entry_age.is_none() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if maintenance.is_active() {
+            continue;
+        }
+
+        if let Err(e) = purge_all_users(&config, &state).await {
+            warn!("Failed to purge expired documents: {e}");
+        }
+    }
+}
+
+async fn purge_all_users(config: &StorageConfig, state: &StorageState) -> io::Result<()> {
+    for user_id in user_ids(config).await? {
+        let storage = UserStorage::new(config.clone(), state.clone(), &user_id);
+
+        match storage.purge_expired().await {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {purged} expired documents of {user_id}"),
+            Err(e) => warn!("Failed to purge expired documents of {user_id}: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Periodically counts the users and documents in the storage for the metrics, so scrapes don't
+/// have to scan it every time
+pub async fn count_documents_periodically(
+    config: StorageConfig,
+    state: StorageState,
+    refresh_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(refresh_interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = refresh_totals(&config, &state).await {
+            warn!("Failed to count documents: {e}");
+        }
+    }
+}
+
+pub(crate) async fn refresh_totals(config: &StorageConfig, state: &StorageState) -> io::Result<()> {
+    let user_ids = user_ids(config).await?;
+    let mut documents = 0;
+
+    for user_id in &user_ids {
+        let storage = UserStorage::new(config.clone(), state.clone(), user_id);
+        documents += storage.list().await?.len();
+    }
+
+    state.metrics.set_storage_totals(user_ids.len(), documents);
+    Ok(())
+}
+
+async fn user_ids(config: &StorageConfig) -> io::Result<Vec<String>> {
+    let mut users = match fs::read_dir(&config.location).await {
+        Ok(users) => users,
+        // Nobody has stored anything yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut user_ids = Vec::new();
+
+    while let Some(entry) = users.next_entry().await? {
+        // Skips files as well as hidden directories like the git repository
+        let Some(user_id) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+
+        if user_id.starts_with('.') || !entry.file_type().await?.is_dir() {
+            continue;
+        }
+
+        user_ids.push(user_id);
+    }
+
+    Ok(user_ids)
+}
+
+// Valid UTF-8 can still be binary garbage, which shows as a high share of control characters
+fn looks_binary(contents: &str, threshold: f64) -> bool {
+    let (mut total, mut control) = (0usize, 0usize);
+
+    for c in contents.chars() {
+        total += 1;
+
+        if c.is_control() && !matches!(c, '\n' | '\r' | '\t') {
+            control += 1;
+        }
+    }
+
+    total > 0 && control as f64 / total as f64 > threshold
+}
+
+pub fn truncate_at_char_boundary(contents: &mut String, len: usize) {
+    if len < contents.len() {
+        let boundary = (0..=len)
+            .rev()
+            .find(|&i| contents.is_char_boundary(i))
+            .unwrap_or(0);
+
+        contents.truncate(boundary);
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+// Lines within fenced code blocks are left alone as whitespace may be significant there
+fn trim_trailing_whitespace(contents: &str) -> String {
+    let mut in_fence = false;
+
+    contents
+        .split('\n')
+        .map(|line| {
+            let (line, cr) = match line.strip_suffix('\r') {
+                Some(line) => (line, "\r"),
+                None => (line, ""),
+            };
+
+            let is_fence = ["```", "~~~"]
+                .iter()
+                .any(|fence| line.trim_start().starts_with(fence));
+
+            let line = if in_fence && !is_fence {
+                line
+            } else {
+                line.trim_end_matches([' ', '\t'])
+            };
+
+            if is_fence {
+                in_fence = !in_fence;
+            }
+
+            format!("{line}{cr}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for UserStorage
+where
+    S: Send + Sync,
+{
+    type Rejection = <AuthenticatedUser as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthenticatedUser::from_request_parts(parts, state).await?;
+        let config = parts
+            .extensions
+            .get::<StorageConfig>()
+            .expect("missing StorageConfig extension")
+            .clone();
+        let state = parts
+            .extensions
+            .get::<StorageState>()
+            .expect("missing StorageState extension")
+            .clone();
+
+        let request_permit = match config.max_concurrent_per_user {
+            Some(limit) => Some(state.try_acquire_request(&user.subject, limit).ok_or((
+                StatusCode::TOO_MANY_REQUESTS,
+                Html("Too many concurrent requests."),
+            ))?),
+            None => None,
+        };
+
+        let storage = UserStorage {
+            _request_permit: request_permit,
+            ..UserStorage::new(config, state, user.subject)
+        };
+
+        // Seeding is a write too, it happens with the first request after the maintenance window
+        let in_maintenance = parts
+            .extensions
+            .get::<Maintenance>()
+            .is_some_and(Maintenance::is_active);
+
+        if let Some(welcome_entry) = storage
+            .config
+            .welcome_entry
+            .clone()
+            .filter(|_| !in_maintenance)
+        {
+            if let Err(e) = storage.seed(&welcome_entry).await {
+                warn!("Failed to seed welcome entry: {e}");
+            }
+        }
+
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::auth::oidc::{self, AuthClient};
+
+    /// Defaults matching an unconfigured server, storing in a fresh temporary directory
+    pub(crate) fn config() -> StorageConfig {
+        StorageConfig {
+            location: std::env::temp_dir()
+                .join(format!("jrnl-test-{:016x}", rand::random::<u64>())),
+            fallback_encoding: None,
+            trim_trailing_whitespace: false,
+            ensure_final_newline: false,
+            reject_binary_threshold: None,
+            max_document_bytes: 1024 * 1024,
+            max_tags_per_document: 50,
+            tag_limit_mode: TagLimitMode::Reject,
+            autosplit_bytes: None,
+            large_document_bytes: None,
+            max_listing: 500,
+            max_preview_len: 16 * 1024,
+            read_concurrency: 16,
+            timezone: time_tz::timezones::get_by_name("UTC").expect("missing UTC timezone"),
+            max_concurrent_per_user: None,
+            templates_dir: None,
+            fsync: FsyncPolicy::None,
+            gated_features: Vec::new(),
+            welcome_entry: None,
+            mirror: None,
+            max_entry_age: None,
+        }
+    }
+
+    pub(crate) fn storage(config: StorageConfig) -> UserStorage {
+        UserStorage::new(config, StorageState::default(), "alice")
+    }
+
+    pub(crate) fn document(identifier: u64, contents: &str) -> Document {
+        Document::new(DocumentIdentifier(identifier), contents.to_owned())
+    }
+
+    #[test]
+    fn large_identifiers_round_trip_as_strings() {
+        // Well beyond 2^53, where JavaScript numbers start losing precision
+        let identifier = DocumentIdentifier(u64::MAX - 1);
+
+        let json = identifier
+            .serialize_as(true, serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json, "18446744073709551614");
+        assert_eq!(
+            serde_json::from_value::<DocumentIdentifier>(json).unwrap(),
+            identifier
+        );
+
+        let json = identifier
+            .serialize_as(false, serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_value::<DocumentIdentifier>(json).unwrap(),
+            identifier
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_are_mirrored() {
+        let config = config();
+        let mirror = config.location.with_extension("mirror");
+        let storage = storage(StorageConfig {
+            mirror: Some(mirror.clone()),
+            ..config.clone()
+        });
+
+        let created = storage.create("Created".into()).await.unwrap();
+        storage.write(document(1, "Written")).await.unwrap();
+
+        for (identifier, contents) in [(created, "Created"), (DocumentIdentifier(1), "Written")] {
+            let primary = storage.doc_path(identifier).unwrap();
+            let mirrored = storage.mirror_path(&primary).unwrap();
+            assert!(mirrored.starts_with(&mirror));
+            assert_eq!(fs::read_to_string(&primary).await.unwrap(), contents);
+            assert_eq!(fs::read_to_string(&mirrored).await.unwrap(), contents);
+        }
+
+        fs::remove_dir_all(&config.location).await.unwrap();
+        fs::remove_dir_all(&mirror).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn size_limit_applies_to_every_write() {
+        let storage = storage(StorageConfig {
+            max_document_bytes: 8,
+            ..config()
+        });
+
+        storage.write(document(1, "12345678")).await.unwrap();
+        let err = storage.write(document(2, "123456789")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        let err = storage.create("123456789".into()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        let err = storage
+            .patch(DocumentIdentifier(1), PatchOperation::Append("9".into()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        // Nothing was touched by the rejected writes
+        assert!(!storage.exists(DocumentIdentifier(2)).await.unwrap());
+        assert_eq!(
+            storage
+                .read(DocumentIdentifier(1), None)
+                .await
+                .unwrap()
+                .contents,
+            "12345678"
+        );
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    fn chunks(chunks: &[&[u8]]) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn append_stream_reassembles_split_characters() {
+        let storage = storage(config());
+        storage.write(document(1, "Grüße ")).await.unwrap();
+
+        // "ä" is 0xC3 0xA4 and "€" is 0xE2 0x82 0xAC, both split across chunks
+        let stream = chunks(&[b"\xC3", b"\xA4 \xE2\x82", b"\xAC!"]);
+        storage
+            .append_stream(DocumentIdentifier(1), stream)
+            .await
+            .unwrap();
+
+        let document = storage.read(DocumentIdentifier(1), None).await.unwrap();
+        assert_eq!(document.contents, "Grüße ä €!");
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_stream_is_all_or_nothing() {
+        let storage = storage(StorageConfig {
+            max_document_bytes: 8,
+            ..config()
+        });
+        storage.write(document(1, "1234")).await.unwrap();
+
+        let err = storage
+            .append_stream(DocumentIdentifier(1), chunks(&[b"56", b"789"]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        let err = storage
+            .append_stream(DocumentIdentifier(1), chunks(&[b"5", b"\xC3"]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let document = storage.read(DocumentIdentifier(1), None).await.unwrap();
+        assert_eq!(document.contents, "1234");
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_stream_normalizes_like_other_writes() {
+        let storage = storage(StorageConfig {
+            trim_trailing_whitespace: true,
+            ensure_final_newline: true,
+            ..config()
+        });
+
+        storage
+            .append_stream(DocumentIdentifier(1), chunks(&[b"line  \n", b"more"]))
+            .await
+            .unwrap();
+
+        let document = storage.read(DocumentIdentifier(1), None).await.unwrap();
+        assert_eq!(document.contents, "line\nmore\n");
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_fsync_covers_every_write_path() {
+        for fsync in [FsyncPolicy::Data, FsyncPolicy::Full] {
+            let storage = storage(StorageConfig { fsync, ..config() });
+
+            // Creates the file through the append, which has to sync the directory as well
+            storage
+                .append_stream(DocumentIdentifier(1), chunks(&[b"appended"]))
+                .await
+                .unwrap();
+            storage.write(document(2, "written")).await.unwrap();
+            storage
+                .patch(
+                    DocumentIdentifier(2),
+                    PatchOperation::Append(" patched".into()),
+                )
+                .await
+                .unwrap();
+            let created = storage.create("created".into()).await.unwrap();
+
+            assert_eq!(storage.list().await.unwrap().len(), 3);
+            assert_eq!(
+                storage.read(created, None).await.unwrap().contents,
+                "created"
+            );
+
+            fs::remove_dir_all(&storage.config.location).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_documents_are_inaccessible_and_purged() {
+        let storage = storage(StorageConfig {
+            max_entry_age: Some(Duration::from_secs(24 * 60 * 60)),
+            ..config()
+        });
+
+        // Writes refuse expired identifiers, so the old document is planted directly
+        let expired = DocumentIdentifier(1000);
+        fs::create_dir_all(&storage.path).await.unwrap();
+        fs::write(storage.doc_path(expired).unwrap(), "old")
+            .await
+            .unwrap();
+        let recent = storage.create("new".into()).await.unwrap();
+
+        let listing = storage.list().await.unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].identifier, recent);
+
+        assert!(!storage.exists(expired).await.unwrap());
+        let not_found =
+            |result: io::Result<_>| result.unwrap_err().kind() == io::ErrorKind::NotFound;
+        assert!(not_found(storage.read(expired, None).await.map(|_| ())));
+        assert!(not_found(storage.write(document(1000, "new")).await));
+        assert!(not_found(
+            storage
+                .patch(expired, PatchOperation::Append("more".into()))
+                .await
+        ));
+        assert!(not_found(
+            storage.append_stream(expired, chunks(&[b"more"])).await
+        ));
+
+        assert_eq!(storage.purge_expired().await.unwrap(), 1);
+        assert!(!fs::try_exists(storage.doc_path(expired).unwrap())
+            .await
+            .unwrap());
+        assert_eq!(storage.read(recent, None).await.unwrap().contents, "new");
+        assert_eq!(storage.purge_expired().await.unwrap(), 0);
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    // Extracts the storage like a request of the subject would, authenticated by the mock IdP
+    async fn extract(
+        auth_client: &AuthClient,
+        config: &StorageConfig,
+        state: &StorageState,
+        subject: &str,
+    ) -> Result<UserStorage, (StatusCode, Html<&'static str>)> {
+        let request = axum::http::Request::builder()
+            .header("cookie", format!("refresh=r-{subject}"))
+            .extension(auth_client.clone())
+            .extension(config.clone())
+            .extension(state.clone())
+            .body(())
+            .unwrap();
+        let (mut parts, _) = request.into_parts();
+
+        UserStorage::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_per_user_limit_are_throttled() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let config = StorageConfig {
+            max_concurrent_per_user: Some(2),
+            ..config()
+        };
+        let state = StorageState::default();
+
+        // Each extracted storage holds its permit like a request that is still being handled
+        let request = |subject| extract(&auth_client, &config, &state, subject);
+
+        let first = request("alice").await.unwrap();
+        let _second = request("alice").await.unwrap();
+        let overflow = request("alice").await.map(|_| ()).unwrap_err();
+        assert_eq!(overflow.0, StatusCode::TOO_MANY_REQUESTS);
+
+        // Other users have their own limit
+        assert!(request("bob").await.is_ok());
+
+        drop(first);
+        assert!(request("alice").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_new_users_are_welcomed() {
+        let idp = oidc::tests::mock_idp().await;
+        let auth_client = oidc::tests::client(&idp).await;
+        let config = StorageConfig {
+            welcome_entry: Some("Welcome!".into()),
+            ..config()
+        };
+        let state = StorageState::default();
+        let contents = |storage: UserStorage| async move {
+            let listing = storage.list().await.unwrap();
+            let identifiers: Vec<_> = listing.iter().map(|m| m.identifier).collect();
+            let documents = storage.entries(&listing, None).await.unwrap();
+            assert_eq!(documents.len(), identifiers.len());
+            documents
+                .into_iter()
+                .map(|d| d.contents)
+                .collect::<Vec<_>>()
+        };
+
+        UserStorage::new(config.clone(), state.clone(), "bob")
+            .create("Existing entry".into())
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let alice = extract(&auth_client, &config, &state, "alice")
+                .await
+                .unwrap();
+            assert_eq!(contents(alice).await, ["Welcome!"]);
+
+            let bob = extract(&auth_client, &config, &state, "bob").await.unwrap();
+            assert_eq!(contents(bob).await, ["Existing entry"]);
+        }
+
+        fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_creates_get_distinct_increasing_identifiers() {
+        let config = config();
+        let state = StorageState::default();
+
+        let creates = (0..32).map(|i| {
+            let storage = UserStorage::new(config.clone(), state.clone(), "alice");
+            tokio::spawn(async move { storage.create(format!("Entry {i}")).await.unwrap() })
+        });
+        let mut identifiers = futures::future::try_join_all(creates).await.unwrap();
+
+        identifiers.sort();
+        identifiers.dedup();
+        assert_eq!(identifiers.len(), 32);
+
+        let storage = UserStorage::new(config.clone(), state, "alice");
+        let next = storage.create("Later".into()).await.unwrap();
+        assert!(identifiers.iter().all(|identifier| *identifier < next));
+        assert_eq!(storage.list().await.unwrap().len(), 33);
+
+        fs::remove_dir_all(&config.location).await.unwrap();
+    }
+
+    #[test]
+    fn normalization_is_opt_in() {
+        let messy = "line  \n```\ncode  \n```\t\r\nend\t\n\n\n";
+
+        let untouched = storage(config());
+        assert_eq!(untouched.normalize(messy.into()), messy);
+
+        let trimmed = storage(StorageConfig {
+            trim_trailing_whitespace: true,
+            ..config()
+        });
+        assert_eq!(
+            trimmed.normalize(messy.into()),
+            "line\n```\ncode  \n```\r\nend\n\n\n"
+        );
+
+        let terminated = storage(StorageConfig {
+            ensure_final_newline: true,
+            ..config()
+        });
+        assert_eq!(
+            terminated.normalize(messy.into()),
+            "line  \n```\ncode  \n```\t\r\nend\t\n"
+        );
+        assert_eq!(terminated.normalize("no newline".into()), "no newline\n");
+        assert_eq!(terminated.normalize(String::new()), "");
+    }
+
+    #[tokio::test]
+    async fn latin1_documents_are_transcoded() {
+        let latin1 = encoding_rs::WINDOWS_1252;
+        let config = StorageConfig {
+            fallback_encoding: Some(latin1),
+            ..config()
+        };
+        let transcoding = storage(config.clone());
+        fs::create_dir_all(&transcoding.path).await.unwrap();
+        fs::write(
+            transcoding.doc_path(DocumentIdentifier(1)).unwrap(),
+            b"Gr\xFC\xDFe",
+        )
+        .await
+        .unwrap();
+
+        match transcoding
+            .read_decoded(DocumentIdentifier(1))
+            .await
+            .unwrap()
+        {
+            DecodedContents::Text {
+                contents,
+                source_encoding,
+            } => {
+                assert_eq!(contents, "Grüße");
+                assert_eq!(source_encoding, Some(latin1));
+            }
+            DecodedContents::Binary(_) => panic!("latin-1 document was not transcoded"),
+        }
+
+        // Without a fallback the bytes are passed through untouched
+        let plain = storage(StorageConfig {
+            fallback_encoding: None,
+            ..config
+        });
+        match plain.read_decoded(DocumentIdentifier(1)).await.unwrap() {
+            DecodedContents::Binary(bytes) => assert_eq!(bytes, b"Gr\xFC\xDFe"),
+            DecodedContents::Text { .. } => panic!("invalid UTF-8 was decoded"),
+        }
+
+        fs::remove_dir_all(&plain.config.location).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failing_writes_leave_the_original_untouched() {
+        let storage = storage(config());
+        storage.write(document(1, "original")).await.unwrap();
+        let path = storage.doc_path(DocumentIdentifier(1)).unwrap();
+
+        // Fails after part of the new contents has been written
+        let failing = tokio_util::io::StreamReader::new(futures::stream::iter([
+            Ok(Bytes::from_static(b"partial")),
+            Err(io::Error::other("disk on fire")),
+        ]));
+        let err = storage
+            .write_bytes(path.clone(), failing)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "disk on fire");
+
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "original");
+        let mut entries = fs::read_dir(&storage.path).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["1.md"], "temporary file was left behind");
+
+        fs::remove_dir_all(&storage.config.location).await.unwrap();
+    }
+
+    #[test]
+    fn crafted_identifiers_stay_inside_the_user_directory() {
+        for crafted in ["\"../../etc/passwd\"", "\"1/../2\"", "\"-1\"", "\"1.md\""] {
+            assert!(serde_json::from_str::<DocumentIdentifier>(crafted).is_err());
+        }
+
+        for crafted in ["../1.md", "1.md/..", "+1.md", "01.md", "1.md.md", ".md"] {
+            assert_eq!(DocumentIdentifier::from_file_name(crafted), None);
+        }
+
+        let storage = storage(config());
+        for identifier in [0, 1, u64::MAX] {
+            let path = storage.doc_path(DocumentIdentifier(identifier)).unwrap();
+            assert_eq!(path.parent(), Some(storage.path.as_path()));
+        }
+    }
+
+    #[test]
+    fn binary_contents_are_rejected_when_configured() {
+        let config = StorageConfig {
+            reject_binary_threshold: Some(0.1),
+            ..config()
+        };
+
+        let err = config.validate("\0\u{1}\u{2}\u{3}text").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(config.validate("plain text\twith tabs\r\n").is_ok());
     }
 }
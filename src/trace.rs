@@ -0,0 +1,98 @@
+use axum::http::Request;
+use std::borrow::Cow;
+use tower_http::trace::MakeSpan;
+use tracing::{Level, Span};
+
+// Path segment followed by a share token, which grants access to a document on its own
+const SHARED_SEGMENT: &str = "shared";
+const REDACTED: &str = "{redacted}";
+
+/// Request spans that never contain query strings (e.g. the authorization code passed to the
+/// callback) or share tokens, so logs can be passed around without leaking credentials
+#[derive(Clone)]
+pub struct MakeRequestSpan {
+    level: Level,
+}
+
+impl MakeRequestSpan {
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+}
+
+impl<B> MakeSpan<B> for MakeRequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let route = route(request);
+
+        // Span levels have to be known at compile time
+        macro_rules! make_span {
+            ($level:expr) => {
+                tracing::span!(
+                    $level,
+                    "request",
+                    method = %request.method(),
+                    route = %route,
+                    version = ?request.version(),
+                )
+            };
+        }
+
+        match self.level {
+            Level::ERROR => make_span!(Level::ERROR),
+            Level::WARN => make_span!(Level::WARN),
+            Level::INFO => make_span!(Level::INFO),
+            Level::DEBUG => make_span!(Level::DEBUG),
+            Level::TRACE => make_span!(Level::TRACE),
+        }
+    }
+}
+
+// Spans are made before routing, so route templates aren't known yet and requests are logged
+// by their path instead
+fn route<B>(request: &Request<B>) -> Cow<'_, str> {
+    let path = request.uri().path();
+
+    if !path.split('/').any(|segment| segment == SHARED_SEGMENT) {
+        return Cow::Borrowed(path);
+    }
+
+    let mut previous = "";
+    let segments: Vec<_> = path
+        .split('/')
+        .map(|segment| {
+            let logged = if previous == SHARED_SEGMENT {
+                REDACTED
+            } else {
+                segment
+            };
+            previous = segment;
+            logged
+        })
+        .collect();
+
+    Cow::Owned(segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn paths_lose_query_and_share_tokens() {
+        assert_eq!(
+            route(&request("/auth/callback?code=secret")),
+            "/auth/callback"
+        );
+        assert_eq!(
+            route(&request("/api/shared/token/x")),
+            "/api/shared/{redacted}/x"
+        );
+        assert_eq!(route(&request("/api/shared")), "/api/shared");
+        assert_eq!(route(&request("/index.html")), "/index.html");
+    }
+}